@@ -0,0 +1,479 @@
+//! Variants of the OTA functions for flash drivers implementing the `embedded-storage-async` traits,
+//! so erasing the ota app partition and writing each sector don't block the executor.
+//!
+//! The `_with_layout` variants support partition tables that deviate from the defaults, see `OtaLayout`.
+//! None of the `OtaOptions` are supported, the blocking functions in the crate root remain available for those.
+
+use crate::error::{AsyncOtaInternalError, AsyncOtaUpdateError};
+use crate::image::ESP_IMAGE_MAGIC;
+use crate::partitions::OtaLayout;
+use crate::ota_data::{is_bootable, select_ota_data, target_copy, transition_hook, OtaTransition};
+use crate::ota_data_structs::{EspOTAData, EspOTAState};
+use crate::{booted_slot, next_update_seq, ota_slot, OtaOutcome, UpdateGuard, FLASH_RETRIES, SECTOR_SIZE};
+use core::sync::atomic::Ordering;
+use embedded_io_async::Read;
+use embedded_storage_async::nor_flash::NorFlash;
+use esp_partition_table::{
+    AppPartitionType, DataPartitionType, PartitionEntry, PartitionError, PartitionReaderState,
+    PartitionType,
+};
+
+/// Starts a new OTA update, like `ota_begin` but for an async flash driver.
+pub async fn ota_begin_async<S: NorFlash, R: Read>(
+    storage: &mut S,
+    binary: R,
+    progress_fn: impl FnMut(usize),
+) -> Result<OtaOutcome, AsyncOtaUpdateError<S::Error, R::Error>> {
+    ota_begin_async_with_layout(storage, binary, progress_fn, &OtaLayout::default()).await
+}
+
+/// Starts a new OTA update, like `ota_begin_async` but for a partition table with the given layout.
+/// Like `ota_begin_with_layout`, the failure counter of the layout is incremented before the new boot entry is written.
+pub async fn ota_begin_async_with_layout<S: NorFlash, R: Read>(
+    storage: &mut S,
+    mut binary: R,
+    mut progress_fn: impl FnMut(usize),
+    layout: &OtaLayout<'_>,
+) -> Result<OtaOutcome, AsyncOtaUpdateError<S::Error, R::Error>> {
+    // Check if there is already an update happening
+    let Some(_guard) = UpdateGuard::acquire() else {
+        return Err(AsyncOtaUpdateError::AlreadyUpdating);
+    };
+
+    // Check if we're in a valid state
+    let ota_data = read_ota_data_async_with_layout(storage, layout).await?;
+    if !ota_data.is_valid() {
+        return Err(AsyncOtaUpdateError::PendingVerify);
+    }
+
+    // Find partition to write to
+    let booted_seq = ota_data.seq;
+    let ota_count = count_ota_partitions_async_with_layout(storage, layout).await?;
+    // Whether there is a factory app only matters while the ota data is blank
+    let has_factory = booted_seq != 0
+        || match find_partition_by_type_async_with_layout(storage, layout, PartitionType::App(AppPartitionType::Factory)).await {
+            Ok(_) => true,
+            Err(AsyncOtaInternalError::PartitionNotFound) => false,
+            Err(e) => return Err(e.into()),
+        };
+    let new_seq = next_update_seq(booted_seq, ota_count, has_factory)
+        .ok_or(AsyncOtaInternalError::SequenceExhausted)?;
+    let new_part = ota_slot(new_seq, ota_count);
+    if booted_slot(booted_seq, ota_count, has_factory) == Some(new_part) {
+        return Err(AsyncOtaUpdateError::WouldEraseRunningPartition);
+    }
+    let ota_app = find_partition_by_type_async_with_layout(
+        storage,
+        layout,
+        PartitionType::App(AppPartitionType::Ota(new_part)),
+    )
+    .await?;
+    info!("Starting OTA update. Current sequence is {booted_seq}, updating to sequence {new_seq} (partition {}).", ota_app.name());
+
+    // Erase partition
+    retry_flash_async(async || storage.erase(ota_app.offset, ota_app.offset + ota_app.size as u32).await)
+        .await
+        .map_err(AsyncOtaInternalError::StorageError)?;
+
+    // Write a non-bootable entry for the new sequence, so an interrupted update keeps booting the old firmware
+    write_pending_ota_data_async(storage, layout, new_seq).await?;
+
+    // Write ota data to flash
+    #[cfg(feature = "sha256")]
+    let mut verifier = crate::verify::ImageHashVerifier::new();
+    let mut data_written = 0;
+    let mut first_byte = None;
+    loop {
+        let mut data_buffer = [0; SECTOR_SIZE];
+        let mut read_len = 0;
+
+        let mut is_done = false;
+        while read_len < SECTOR_SIZE {
+            let read = binary
+                .read(&mut data_buffer[read_len..])
+                .await
+                .map_err(AsyncOtaUpdateError::ReadError)?;
+            if read == 0 {
+                is_done = true;
+                break;
+            }
+            read_len += read;
+        }
+
+        if data_written + read_len > ota_app.size {
+            return Err(AsyncOtaUpdateError::OutOfSpace {
+                partition_size: ota_app.size,
+                bytes_written: data_written + read_len,
+            });
+        }
+        #[cfg(feature = "sha256")]
+        verifier.feed(&data_buffer[0..read_len]);
+
+        // Pad the last chunk with erased bytes up to a multiple of the flash's `WRITE_SIZE`
+        let write_len = read_len.next_multiple_of(S::WRITE_SIZE);
+        data_buffer[read_len..write_len].fill(0xFF);
+        let offset = ota_app.offset + data_written as u32;
+        retry_flash_async(async || storage.write(offset, &data_buffer[0..write_len]).await)
+            .await
+            .map_err(AsyncOtaInternalError::StorageError)?;
+
+        if data_written == 0 {
+            first_byte = data_buffer[0..read_len].first().copied();
+        }
+        data_written += read_len;
+        progress_fn(data_written);
+
+        if is_done {
+            break;
+        }
+    }
+
+    // Verify the digest esptool appended to the image, if any
+    #[cfg(feature = "sha256")]
+    if verifier.finish() == Some(false) {
+        return Err(AsyncOtaUpdateError::HashMismatch);
+    }
+
+    // The bootloader refuses to boot anything that doesn't start with the image magic,
+    // failing here leaves the partition partially written but the boot entry unchanged
+    if first_byte != Some(ESP_IMAGE_MAGIC) {
+        return Err(AsyncOtaUpdateError::InvalidImageMagic);
+    }
+
+    // Only updates that will be booted can cause a boot loop, so failed updates are not counted
+    if let Some(failure_counter) = layout.failure_counter {
+        increment_failure_count_async(storage, layout, failure_counter).await?;
+    }
+
+    // Write new OTA data boot entry
+    let data = EspOTAData::new(new_seq, [0xFF; 20]);
+    write_ota_data_async_with_layout(storage, layout, data).await?;
+
+    Ok(OtaOutcome {
+        bytes_written: data_written,
+        #[cfg(feature = "sha256")]
+        sha256: None,
+    })
+}
+
+/// Run a flash operation, retrying it as configured with `set_flash_retries`, like `retry_flash` does for the blocking functions
+async fn retry_flash_async<T, E>(mut op: impl AsyncFnMut() -> Result<T, E>) -> Result<T, E> {
+    let mut retries = FLASH_RETRIES.load(Ordering::SeqCst);
+    loop {
+        match op().await {
+            Err(_) if retries > 0 => {
+                warn!("Flash operation failed, retrying ({retries} retries left)");
+                retries -= 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Find partition entry by type, like `partitions::find_partition_by_type` but for an async flash driver
+pub async fn find_partition_by_type_async<S: NorFlash>(
+    storage: &mut S,
+    typ: PartitionType,
+) -> Result<PartitionEntry, AsyncOtaInternalError<S::Error>> {
+    find_partition_by_type_async_with_layout(storage, &OtaLayout::default(), typ).await
+}
+
+/// Find partition entry by type, like `find_partition_by_type_async` but for a partition table with the given layout
+pub async fn find_partition_by_type_async_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout<'_>,
+    typ: PartitionType,
+) -> Result<PartitionEntry, AsyncOtaInternalError<S::Error>> {
+    find_partition_async(storage, layout, |entry| entry.type_ == typ).await
+}
+
+/// Find the only partition entry `is_match` accepts, in the parsed partitions if the layout has them
+async fn find_partition_async<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout<'_>,
+    is_match: impl Fn(&PartitionEntry) -> bool,
+) -> Result<PartitionEntry, AsyncOtaInternalError<S::Error>> {
+    if let Some(partitions) = layout.partitions {
+        let mut matches = partitions.iter().filter(|entry| is_match(entry));
+        let found_partition = matches.next().ok_or(AsyncOtaInternalError::PartitionNotFound)?;
+        if matches.next().is_some() {
+            return Err(AsyncOtaInternalError::PartitionFoundTwice);
+        }
+        return Ok(found_partition.clone());
+    }
+
+    let table = layout.table();
+    let mut state = PartitionReaderState::new(table.addr, table.size, false);
+    let mut found_partition = None;
+
+    while let Some(entry) = next_partition_async(storage, &mut state).await? {
+        if is_match(&entry) {
+            if found_partition.is_none() {
+                found_partition = Some(entry);
+            } else {
+                return Err(AsyncOtaInternalError::PartitionFoundTwice);
+            }
+        }
+    }
+
+    found_partition.ok_or(AsyncOtaInternalError::PartitionNotFound)
+}
+
+/// Count the ota app partitions, like `partitions::count_ota_partitions` but for an async flash driver
+pub async fn count_ota_partitions_async<S: NorFlash>(
+    storage: &mut S,
+) -> Result<usize, AsyncOtaInternalError<S::Error>> {
+    count_ota_partitions_async_with_layout(storage, &OtaLayout::default()).await
+}
+
+/// Count the ota app partitions, like `count_ota_partitions_async` but for a partition table with the given layout
+pub async fn count_ota_partitions_async_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout<'_>,
+) -> Result<usize, AsyncOtaInternalError<S::Error>> {
+    if let Some(partitions) = layout.partitions {
+        return Ok(partitions
+            .iter()
+            .filter(|entry| matches!(entry.type_, PartitionType::App(AppPartitionType::Ota(_))))
+            .count());
+    }
+
+    let table = layout.table();
+    let mut state = PartitionReaderState::new(table.addr, table.size, false);
+    let mut count = 0;
+
+    while let Some(entry) = next_partition_async(storage, &mut state).await? {
+        if let PartitionType::App(AppPartitionType::Ota(_)) = entry.type_ {
+            count += 1;
+        }
+    }
+
+    Ok(count)
+}
+
+/// Read the next entry of the partition table, returns `None` at the end of the table
+async fn next_partition_async<S: NorFlash>(
+    storage: &mut S,
+    state: &mut PartitionReaderState,
+) -> Result<Option<PartitionEntry>, AsyncOtaInternalError<S::Error>> {
+    if state.is_done() {
+        return Ok(None);
+    }
+
+    let mut buffer = [0; PartitionEntry::SIZE];
+    retry_flash_async(async || storage.read(state.offset(), &mut buffer).await)
+        .await
+        .map_err(AsyncOtaInternalError::StorageError)?;
+    match state.read(&buffer) {
+        Ok(entry) => Ok(Some(entry)),
+        Err(PartitionError::NotEnoughData) => Ok(None),
+        Err(e) => Err(AsyncOtaInternalError::PartitionError(e)),
+    }
+}
+
+/// Find the otadata partition like `partitions::find_ota_data_partition`, by name if the layout specifies one and
+/// otherwise by type. Returns `NoOtaDataPartition` if the partition table doesn't contain one
+/// and `OtaDataTooSmall` if it doesn't fit both copies
+async fn find_ota_data_partition_async<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout<'_>,
+) -> Result<PartitionEntry, AsyncOtaInternalError<S::Error>> {
+    let by_name = match layout.ota_data_name {
+        Some(name) => match find_partition_async(storage, layout, |entry| entry.name() == name).await {
+            Err(AsyncOtaInternalError::PartitionNotFound) => None,
+            result => Some(result?),
+        },
+        None => None,
+    };
+    let ota_data_part = match by_name {
+        Some(partition) => partition,
+        None => match find_partition_by_type_async_with_layout(
+            storage,
+            layout,
+            PartitionType::Data(DataPartitionType::Ota),
+        )
+        .await
+        {
+            Err(AsyncOtaInternalError::PartitionNotFound) => {
+                return Err(AsyncOtaInternalError::NoOtaDataPartition)
+            }
+            result => result?,
+        },
+    };
+    if ota_data_part.size < 2 * SECTOR_SIZE {
+        return Err(AsyncOtaInternalError::OtaDataTooSmall {
+            size: ota_data_part.size,
+        });
+    }
+    Ok(ota_data_part)
+}
+
+/// Read from ota data partition, selecting the copy the bootloader boots.
+/// If both copies are erased or no copy may be booted, returns the same blank entry with sequence number 0 as `read_ota_data`.
+pub async fn read_ota_data_async<S: NorFlash>(
+    storage: &mut S,
+) -> Result<EspOTAData, AsyncOtaInternalError<S::Error>> {
+    read_ota_data_async_with_layout(storage, &OtaLayout::default()).await
+}
+
+/// Read from ota data partition, like `read_ota_data_async` but for a partition table with the given layout
+pub async fn read_ota_data_async_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout<'_>,
+) -> Result<EspOTAData, AsyncOtaInternalError<S::Error>> {
+    let ota_data_part = find_ota_data_partition_async(storage, layout).await?;
+    let mut copies = read_ota_data_copies_async(storage, &ota_data_part).await?;
+
+    match select_ota_data(&copies) {
+        Some(selected) if is_bootable(copies[selected].as_ref().unwrap()) => {
+            Ok(copies[selected].take().unwrap())
+        }
+        Some(_) => Ok(EspOTAData::blank()),
+        None if ota_data_is_blank_async(storage, &ota_data_part).await? => Ok(EspOTAData::blank()),
+        None => Err(AsyncOtaInternalError::OtaDataCorrupt),
+    }
+}
+
+/// Returns true if both copies of the ota data are erased
+async fn ota_data_is_blank_async<S: NorFlash>(
+    storage: &mut S,
+    ota_data_part: &PartitionEntry,
+) -> Result<bool, AsyncOtaInternalError<S::Error>> {
+    for copy in 0..2 {
+        let mut buffer = [0; 32];
+        retry_flash_async(async || storage.read(ota_data_part.offset + copy * SECTOR_SIZE as u32, &mut buffer).await)
+            .await
+            .map_err(AsyncOtaInternalError::StorageError)?;
+        if buffer != [0xFF; 32] {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Read both copies of the ota data, a copy is `None` if it is corrupt
+async fn read_ota_data_copies_async<S: NorFlash>(
+    storage: &mut S,
+    ota_data_part: &PartitionEntry,
+) -> Result<[Option<EspOTAData>; 2], AsyncOtaInternalError<S::Error>> {
+    Ok([
+        read_ota_data_copy_async(storage, ota_data_part, 0).await?,
+        read_ota_data_copy_async(storage, ota_data_part, 1).await?,
+    ])
+}
+
+/// Read one of the two copies of the ota data, returns `None` if the copy is corrupt
+async fn read_ota_data_copy_async<S: NorFlash>(
+    storage: &mut S,
+    ota_data_part: &PartitionEntry,
+    copy: u32,
+) -> Result<Option<EspOTAData>, AsyncOtaInternalError<S::Error>> {
+    let mut buffer = [0; 32];
+    retry_flash_async(async || storage.read(ota_data_part.offset + copy * SECTOR_SIZE as u32, &mut buffer).await)
+        .await
+        .map_err(AsyncOtaInternalError::StorageError)?;
+    Ok(EspOTAData::try_from(buffer).ok())
+}
+
+/// Write a boot entry to the ota data partition, like `write_ota_data` only one copy is written
+pub async fn write_ota_data_async<S: NorFlash>(
+    storage: &mut S,
+    data: EspOTAData,
+) -> Result<(), AsyncOtaInternalError<S::Error>> {
+    write_ota_data_async_with_layout(storage, &OtaLayout::default(), data).await
+}
+
+/// Write a boot entry to the ota data partition, like `write_ota_data_async` but for a partition table with the given layout
+pub async fn write_ota_data_async_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout<'_>,
+    data: EspOTAData,
+) -> Result<(), AsyncOtaInternalError<S::Error>> {
+    let ota_data_part = find_ota_data_partition_async(storage, layout).await?;
+    let copies = read_ota_data_copies_async(storage, &ota_data_part).await?;
+    let old = select_ota_data(&copies).and_then(|selected| copies[selected].clone());
+
+    let copy = target_copy(&copies, data.seq);
+    write_ota_data_copy_async(storage, &ota_data_part, copy, data.clone()).await?;
+
+    if let Some(hook) = transition_hook() {
+        hook(OtaTransition {
+            old_seq: old.as_ref().map(|old| old.seq),
+            new_seq: data.seq,
+            old_state: old.as_ref().map(|old| old.state),
+            new_state: data.state,
+        });
+    }
+    Ok(())
+}
+
+/// Write the entry of an update that is still being written, like `ota_data::write_pending_ota_data`
+async fn write_pending_ota_data_async<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout<'_>,
+    seq: u32,
+) -> Result<(), AsyncOtaInternalError<S::Error>> {
+    let ota_data_part = find_ota_data_partition_async(storage, layout).await?;
+    let copies = read_ota_data_copies_async(storage, &ota_data_part).await?;
+
+    let mut data = EspOTAData::new(seq, [0xFF; 20]);
+    data.state = EspOTAState::Aborted;
+    write_ota_data_copy_async(storage, &ota_data_part, target_copy(&copies, seq), data).await
+}
+
+/// Erase and write one of the two copies of the ota data
+async fn write_ota_data_copy_async<S: NorFlash>(
+    storage: &mut S,
+    ota_data_part: &PartitionEntry,
+    copy: u32,
+    data: EspOTAData,
+) -> Result<(), AsyncOtaInternalError<S::Error>> {
+    let buffer: [u8; 32] = data.into();
+    let offset = ota_data_part.offset + copy * SECTOR_SIZE as u32;
+    if !(offset as usize).is_multiple_of(S::WRITE_SIZE) || !buffer.len().is_multiple_of(S::WRITE_SIZE) {
+        return Err(AsyncOtaInternalError::Misaligned { offset });
+    }
+
+    retry_flash_async(async || storage.erase(offset, offset + SECTOR_SIZE as u32).await)
+        .await
+        .map_err(AsyncOtaInternalError::StorageError)?;
+    retry_flash_async(async || storage.write(offset, &buffer).await)
+        .await
+        .map_err(AsyncOtaInternalError::StorageError)?;
+
+    Ok(())
+}
+
+/// Increment the failure counter, like `recovery::increment_failure_count_at`.
+/// The count is stored in the first word of the partition, like the values of the blocking functions.
+async fn increment_failure_count_async<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout<'_>,
+    failure_counter: &str,
+) -> Result<(), AsyncOtaInternalError<S::Error>> {
+    let part = find_partition_async(storage, layout, |entry| entry.name() == failure_counter).await?;
+    let mut buffer = [0xFF; 32];
+    let read_len = 4usize.next_multiple_of(S::READ_SIZE);
+    let write_len = 4usize.next_multiple_of(S::WRITE_SIZE);
+    if read_len > buffer.len() || write_len > buffer.len() {
+        return Err(AsyncOtaInternalError::Misaligned { offset: part.offset });
+    }
+
+    retry_flash_async(async || storage.read(part.offset, &mut buffer[..read_len]).await)
+        .await
+        .map_err(AsyncOtaInternalError::StorageError)?;
+    let count = match u32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) {
+        u32::MAX => 0,
+        count => count,
+    };
+
+    buffer.fill(0xFF);
+    buffer[..4].copy_from_slice(&count.saturating_add(1).to_le_bytes());
+    retry_flash_async(async || storage.erase(part.offset, part.offset + S::ERASE_SIZE as u32).await)
+        .await
+        .map_err(AsyncOtaInternalError::StorageError)?;
+    retry_flash_async(async || storage.write(part.offset, &buffer[..write_len]).await)
+        .await
+        .map_err(AsyncOtaInternalError::StorageError)?;
+    Ok(())
+}
@@ -0,0 +1,70 @@
+//! Updates for firmware with a blocking network stack, that doesn't run an executor.
+
+use crate::error::OtaUpdateError;
+use crate::{ota_begin_with_options, OtaOptions, OtaOutcome};
+use core::convert::Infallible;
+use core::future::Future;
+use core::pin::pin;
+use core::task::{Context, Poll, Waker};
+use embedded_storage::nor_flash::NorFlash;
+
+/// Starts a new OTA update, like `ota_begin` but reading the `binary` with the blocking `embedded_io::Read`.
+/// Returns once the update is finished, with the same result as `ota_begin`.
+pub fn ota_begin_blocking<S: NorFlash, R: embedded_io::Read>(
+    storage: &mut S,
+    binary: R,
+    progress_fn: impl FnMut(usize),
+) -> Result<OtaOutcome, OtaUpdateError<S, R::Error>> {
+    ota_begin_blocking_with_options(storage, binary, progress_fn, OtaOptions::default())
+}
+
+/// Starts a new blocking OTA update, like `ota_begin_blocking` but with the additional behaviour configured in `options`.
+/// The yields of `max_erase_chunk_sectors` return immediately, since there is no executor to yield to.
+pub fn ota_begin_blocking_with_options<S: NorFlash, R: embedded_io::Read>(
+    storage: &mut S,
+    binary: R,
+    progress_fn: impl FnMut(usize),
+    options: OtaOptions<'_>,
+) -> Result<OtaOutcome, OtaUpdateError<S, R::Error>> {
+    block_on(ota_begin_with_options(storage, BlockingRead(binary), progress_fn, options))
+}
+
+/// Starts a new OTA update of an image that is already in memory, e.g. staged in PSRAM.
+/// Blocks until the update is finished, like `ota_begin_blocking`.
+/// The length of the image is known upfront, so an image that doesn't fit fails with `OutOfSpace`
+/// before the partition is erased.
+pub fn ota_begin_from_slice<S: NorFlash>(
+    storage: &mut S,
+    image: &[u8],
+    progress_fn: impl FnMut(usize),
+) -> Result<OtaOutcome, OtaUpdateError<S, Infallible>> {
+    let options = OtaOptions {
+        expected_len: Some(image.len()),
+        ..OtaOptions::default()
+    };
+    ota_begin_blocking_with_options(storage, image, progress_fn, options)
+}
+
+/// Adapts a blocking reader to `embedded_io_async::Read`, its reads complete without ever yielding
+struct BlockingRead<R>(R);
+
+impl<R: embedded_io::ErrorType> embedded_io::ErrorType for BlockingRead<R> {
+    type Error = R::Error;
+}
+
+impl<R: embedded_io::Read> embedded_io_async::Read for BlockingRead<R> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.0.read(buf)
+    }
+}
+
+/// Run a future that only returns `Pending` to yield, i.e. that never waits for an external event
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+    }
+}
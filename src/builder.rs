@@ -0,0 +1,143 @@
+//! A single place to configure an update, instead of choosing between the variants of `ota_begin`.
+
+use crate::error::OtaUpdateError;
+use crate::partitions::OtaLayout;
+use crate::{ota_begin_with_buffer, ota_begin_with_options, OtaOptions, OtaOutcome};
+use embedded_io_async::Read;
+use embedded_storage::nor_flash::NorFlash;
+use esp_partition_table::PartitionTable;
+
+/// Configures an OTA update once, which is then started with `begin`.
+/// The free function `ota_begin` remains the simplest way to do an update with the defaults.
+/// For example `OtaBuilder::new().with_retries(3).verify(true).buffer(&mut buffer).begin(&mut flash, binary, |_| {})`.
+#[derive(Debug, Default)]
+pub struct OtaBuilder<'a> {
+    options: OtaOptions<'a>,
+    buffer: Option<&'a mut [u8]>,
+}
+
+impl<'a> OtaBuilder<'a> {
+    /// An update with the default behaviour, like `ota_begin`
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start from these options, e.g. for the knobs that have no method of their own
+    pub fn with_options(mut self, options: OtaOptions<'a>) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Where to find the partitions used for OTA, see `OtaOptions::layout`
+    pub fn with_layout(mut self, layout: OtaLayout<'a>) -> Self {
+        self.options.layout = layout;
+        self
+    }
+
+    /// Location of the partition table, for bootloaders that place it elsewhere than the default offset of 0x8000
+    pub fn with_partition_table(mut self, table: PartitionTable) -> Self {
+        self.options.layout.partition_table = Some(table);
+        self
+    }
+
+    /// Retry failed flash operations this many times during the update, see `OtaOptions::flash_retries`
+    pub fn with_retries(mut self, retries: u8) -> Self {
+        self.options.flash_retries = Some(retries);
+        self
+    }
+
+    /// Read back every sector after writing it, see `OtaOptions::verify_written`
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.options.verify_written = verify;
+        self
+    }
+
+    /// Length of the image, if it is known upfront, see `OtaOptions::expected_len`
+    pub fn expected_len(mut self, len: usize) -> Self {
+        self.options.expected_len = Some(len);
+        self
+    }
+
+    /// Read the `binary` into this buffer instead of a 4 KiB buffer on the stack, see `ota_begin_with_buffer`
+    pub fn buffer(mut self, buffer: &'a mut [u8]) -> Self {
+        self.buffer = Some(buffer);
+        self
+    }
+
+    /// Starts the configured OTA update, see `ota_begin`
+    pub async fn begin<S: NorFlash, R: Read>(
+        self,
+        storage: &mut S,
+        binary: R,
+        progress_fn: impl FnMut(usize),
+    ) -> Result<OtaOutcome, OtaUpdateError<S, R::Error>> {
+        match self.buffer {
+            Some(buffer) => {
+                ota_begin_with_buffer(storage, binary, progress_fn, self.options, buffer).await
+            }
+            None => ota_begin_with_options(storage, binary, progress_fn, self.options).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use crate::{set_flash_retries, FLASH_RETRIES};
+    use core::pin::pin;
+    use core::sync::atomic::Ordering;
+    use core::future::Future;
+    use core::task::{Context, Waker};
+
+    /// Returns the first chunk of `image`, then never completes
+    struct StallingReader<'a>(Option<&'a [u8]>);
+
+    impl embedded_io_async::ErrorType for StallingReader<'_> {
+        type Error = core::convert::Infallible;
+    }
+
+    impl embedded_io_async::Read for StallingReader<'_> {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            match self.0.take() {
+                Some(data) => {
+                    let len = buf.len().min(data.len());
+                    buf[..len].copy_from_slice(&data[..len]);
+                    Ok(len)
+                }
+                None => core::future::pending().await,
+            }
+        }
+    }
+
+    #[test]
+    fn retries_only_apply_while_the_update_runs() {
+        let _serial = serial();
+        set_flash_retries(1);
+        let image = image(1000);
+
+        let mut flash = MockFlash::new(2);
+        let mut retries_during_update = None;
+        block_on(OtaBuilder::new().with_retries(5).begin(&mut flash, SliceReader::new(&image), |_| {
+            retries_during_update = Some(FLASH_RETRIES.load(Ordering::SeqCst));
+        }))
+        .unwrap();
+        assert_eq!(retries_during_update, Some(5));
+        assert_eq!(FLASH_RETRIES.load(Ordering::SeqCst), 1);
+
+        // An update that is dropped halfway restores the setting as well
+        let mut flash = MockFlash::new(2);
+        {
+            let mut update = pin!(OtaBuilder::new().with_retries(5).begin(
+                &mut flash,
+                StallingReader(Some(&image[..100])),
+                |_| {}
+            ));
+            let mut cx = Context::from_waker(Waker::noop());
+            assert!(update.as_mut().poll(&mut cx).is_pending());
+            assert_eq!(FLASH_RETRIES.load(Ordering::SeqCst), 5);
+        }
+        assert_eq!(FLASH_RETRIES.load(Ordering::SeqCst), 1);
+        set_flash_retries(0);
+    }
+}
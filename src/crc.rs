@@ -1,4 +1,4 @@
-use crc::{Algorithm, Crc};
+use crc::{Algorithm, Crc, Digest, CRC_32_ISO_HDLC};
 
 /// ESP32 CRC32 implementation (`esp_rom_crc32_le`)
 /// This has only been verified to be identical with one input-output pair so use with caution.
@@ -7,6 +7,19 @@ pub(crate) fn esp_crc32(bytes: &[u8; 4]) -> u32 {
     !Crc::<u32>::new(&CRC_32_ESP).checksum(&buffer)
 }
 
+/// The CRC of an otadata entry (`esp_ota_select_entry_t::crc`) with sequence number `seq`,
+/// e.g. to construct an entry by hand. The CRC only covers the sequence number.
+pub fn ota_data_crc(seq: u32) -> u32 {
+    esp_crc32(&seq.to_le_bytes())
+}
+
+/// Standard CRC32 (as used by zlib), so checksums can be compared with host side tooling
+pub(crate) fn crc32_digest() -> Digest<'static, u32> {
+    CRC_32.digest()
+}
+
+static CRC_32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
 const CRC_32_ESP: Algorithm<u32> = Algorithm {
     width: 32,
     poly: 0x04c11db7,
@@ -17,3 +30,15 @@ const CRC_32_ESP: Algorithm<u32> = Algorithm {
     check: 0,
     residue: 0,
 };
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ota_data_crc_matches_esp_idf() {
+        // The CRCs of the entries esp-idf writes for the first two updates
+        assert_eq!(ota_data_crc(1), 0x4743_989a);
+        assert_eq!(ota_data_crc(2), 0x55f6_3774);
+    }
+}
@@ -1,5 +1,8 @@
+use core::fmt::{Debug, Display, Formatter};
 use embedded_storage::nor_flash::NorFlash;
 use esp_partition_table::NorFlashOpError;
+#[cfg(feature = "async-flash")]
+use esp_partition_table::PartitionError;
 
 /// Errors that may occur during an OTA update
 #[derive(Debug)]
@@ -8,10 +11,50 @@ pub enum OtaUpdateError<S: NorFlash, R> {
     /// so it may not start an update before being verified.
     /// See `ota_accept`
     PendingVerify,
-    /// Not enough space in partition
-    OutOfSpace,
+    /// Not enough space in partition. `bytes_written` is the amount of bytes of the `binary` received
+    /// when it no longer fit the `partition_size` (or the `expected_len`), so the image is at least this large
+    OutOfSpace {
+        partition_size: usize,
+        bytes_written: usize,
+    },
     /// Another update is already in progress
     AlreadyUpdating,
+    /// The SHA-256 digest appended to the image does not match the image
+    HashMismatch,
+    /// The binary does not start with the magic byte of an ESP image, e.g. because an error page was downloaded.
+    /// The partition was already written, but the boot entry is unchanged so the old firmware keeps booting.
+    InvalidImageMagic,
+    /// The SHA-256 digest of the binary does not match the `expected_sha256`
+    ChecksumMismatch,
+    /// The image does not contain a valid app description (`esp_app_desc_t`)
+    InvalidAppDescription,
+    /// The `secure_version` of the image is lower than the highest version that was installed before,
+    /// see `OtaOptions::version_store`, or than that of the running app, see `OtaOptions::reject_downgrade`
+    DowngradeBlocked { installed: u32, incoming: u32 },
+    /// The project name in the app description of the image is not the expected one
+    ProjectNameMismatch,
+    /// The sector at this flash offset still contained data after the partition was erased
+    SectorNotErased { offset: u32 },
+    /// The data read back from this flash offset differs from the data that was written,
+    /// the offset is that of the first byte that differs
+    VerifyMismatch { offset: u32 },
+    /// The buffer passed to `ota_begin_with_buffer` is empty or not a multiple of the flash's `WRITE_SIZE`
+    UnalignedBuffer { len: usize },
+    /// The `binary` was not as long as the `expected_len`
+    LengthMismatch { expected: usize, actual: usize },
+    /// A read of the `binary` did not complete in time, see `ota_begin_with_timeout`.
+    /// The partition may be partially written, but the boot entry is unchanged so the old firmware keeps booting.
+    ReadTimeout,
+    /// The signature check passed to `ota_begin_signed` rejected the image, the boot entry is unchanged
+    SignatureInvalid,
+    /// The offset passed to `ota_resume` is not a multiple of the flash's `ERASE_SIZE` or beyond the partition
+    InvalidResumeOffset { offset: usize },
+    /// The `binary` is identical to the running image, see `OtaOptions::skip_if_identical`.
+    /// Nothing was written and the boot entry is unchanged.
+    ImageUnchanged,
+    /// The ota data selects the running partition as the target of the update, e.g. with a single ota slot
+    /// or after the ota data was changed by hand. Nothing was erased, as that would erase the running firmware.
+    WouldEraseRunningPartition,
     /// Read error
     ReadError(R),
     /// Internal error while working with the ota partitions
@@ -30,6 +73,26 @@ pub enum OtaInternalError<S: NorFlash> {
     NorFlashOpError(NorFlashOpError<S>),
     PartitionNotFound,
     PartitionFoundTwice,
+    AlreadyUpdating,
+    OtaDataInconsistent,
+    InvalidAppDescription,
+    NothingToRollBackTo,
+    SequenceExhausted,
+    /// The ota data partition at this offset can't be written with the flash's `WRITE_SIZE`,
+    /// because the offset or the 32 byte length of an entry is not a multiple of it
+    Misaligned { offset: u32 },
+    /// The range to read extends past the end of the partition, see `partitions::read_partition`
+    OutOfBounds,
+    /// The selected boot entry does not have the expected sequence number, see `ota_accept_seq`
+    SequenceMismatch { expected: u32, actual: u32 },
+    /// The partition table has no otadata partition. OTA updates require an otadata partition
+    /// and at least one `ota_` app partition, e.g. the `partitions_two_ota.csv` table of esp-idf,
+    /// a table with only a factory app can't be updated
+    NoOtaDataPartition,
+    /// The otadata partition is smaller than the two sectors that hold the copies of the boot entry
+    OtaDataTooSmall { size: usize },
+    /// The partition table has `count` entries, more than fit the `ParsedPartitions`
+    TooManyPartitions { count: usize },
 }
 
 impl<S: NorFlash> From<NorFlashOpError<S>> for OtaInternalError<S> {
@@ -37,3 +100,323 @@ impl<S: NorFlash> From<NorFlashOpError<S>> for OtaInternalError<S> {
         OtaInternalError::NorFlashOpError(value)
     }
 }
+
+impl<S: NorFlash, R: Debug> Display for OtaUpdateError<S, R> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            OtaUpdateError::PendingVerify => {
+                write!(f, "the running firmware has not been accepted yet")
+            }
+            OtaUpdateError::OutOfSpace {
+                partition_size,
+                bytes_written,
+            } => write!(
+                f,
+                "the image is at least {bytes_written} bytes but the ota partition is {partition_size} bytes"
+            ),
+            OtaUpdateError::AlreadyUpdating => write!(f, "another update is already in progress"),
+            OtaUpdateError::HashMismatch => {
+                write!(f, "the SHA-256 digest appended to the image does not match")
+            }
+            OtaUpdateError::InvalidImageMagic => write!(f, "the binary is not an ESP image"),
+            OtaUpdateError::ChecksumMismatch => {
+                write!(f, "the SHA-256 digest of the binary does not match")
+            }
+            OtaUpdateError::InvalidAppDescription => {
+                write!(f, "the image contains no valid app description")
+            }
+            OtaUpdateError::DowngradeBlocked {
+                installed,
+                incoming,
+            } => write!(
+                f,
+                "secure version {incoming} of the image is lower than installed version {installed}"
+            ),
+            OtaUpdateError::ProjectNameMismatch => write!(f, "the image is for another project"),
+            OtaUpdateError::SectorNotErased { offset } => {
+                write!(f, "the sector at 0x{offset:x} was not erased")
+            }
+            OtaUpdateError::VerifyMismatch { offset } => {
+                write!(
+                    f,
+                    "the data read back at 0x{offset:x} differs from the data written"
+                )
+            }
+            OtaUpdateError::UnalignedBuffer { len } => {
+                write!(f, "buffer length {len} is not a non-zero multiple of the flash write size")
+            }
+            OtaUpdateError::LengthMismatch { expected, actual } => {
+                write!(
+                    f,
+                    "expected {expected} bytes but the binary was {actual} bytes"
+                )
+            }
+            OtaUpdateError::ReadTimeout => write!(f, "reading the binary timed out"),
+            OtaUpdateError::SignatureInvalid => write!(f, "the signature of the image is invalid"),
+            OtaUpdateError::InvalidResumeOffset { offset } => {
+                write!(f, "can't resume the update at offset 0x{offset:x}")
+            }
+            OtaUpdateError::ImageUnchanged => write!(f, "the image is already running"),
+            OtaUpdateError::WouldEraseRunningPartition => {
+                write!(f, "the update would overwrite the running partition")
+            }
+            OtaUpdateError::ReadError(e) => write!(f, "error reading the binary: {e:?}"),
+            OtaUpdateError::InternalError(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl<S: NorFlash + Debug + 'static, R: Debug> core::error::Error for OtaUpdateError<S, R> {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            OtaUpdateError::InternalError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl<S: NorFlash> Display for OtaInternalError<S> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            OtaInternalError::OtaDataCorrupt => {
+                write!(f, "both copies of the ota data are corrupt")
+            }
+            OtaInternalError::NorFlashOpError(e) => write!(f, "{}", FlashOpError::from_ref(e)),
+            OtaInternalError::PartitionNotFound => write!(f, "partition not found"),
+            OtaInternalError::PartitionFoundTwice => write!(f, "partition found twice"),
+            OtaInternalError::AlreadyUpdating => write!(f, "an update is in progress"),
+            OtaInternalError::OtaDataInconsistent => {
+                write!(f, "the ota data points at a partition without an image")
+            }
+            OtaInternalError::InvalidAppDescription => write!(f, "no valid app description"),
+            OtaInternalError::NothingToRollBackTo => write!(f, "no previous app to roll back to"),
+            OtaInternalError::SequenceExhausted => {
+                write!(f, "the sequence number of the ota data can't be increased any further")
+            }
+            OtaInternalError::Misaligned { offset } => {
+                write!(f, "the ota data at 0x{offset:x} is not aligned to the flash write size")
+            }
+            OtaInternalError::OutOfBounds => write!(f, "the range is outside of the partition"),
+            OtaInternalError::SequenceMismatch { expected, actual } => {
+                write!(f, "expected sequence number {expected} but the ota data is at {actual}")
+            }
+            OtaInternalError::NoOtaDataPartition => {
+                write!(f, "the partition table has no otadata partition")
+            }
+            OtaInternalError::OtaDataTooSmall { size } => {
+                write!(f, "the otadata partition is {size} bytes, it needs at least two sectors")
+            }
+            OtaInternalError::TooManyPartitions { count } => {
+                write!(f, "the partition table has {count} entries, more than fit")
+            }
+        }
+    }
+}
+
+impl<S: NorFlash + Debug + 'static> core::error::Error for OtaInternalError<S> {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            OtaInternalError::NorFlashOpError(e) => Some(FlashOpError::from_ref(e)),
+            _ => None,
+        }
+    }
+}
+
+/// The `NorFlashOpError` of an `OtaInternalError` as its `Error::source`,
+/// since `esp-partition-table` doesn't implement `Error` for it
+#[derive(Debug)]
+#[repr(transparent)]
+struct FlashOpError<S: NorFlash>(NorFlashOpError<S>);
+
+impl<S: NorFlash> FlashOpError<S> {
+    fn from_ref(error: &NorFlashOpError<S>) -> &Self {
+        // SAFETY: `FlashOpError` is a `repr(transparent)` wrapper of `NorFlashOpError`
+        unsafe { &*(error as *const NorFlashOpError<S> as *const Self) }
+    }
+}
+
+impl<S: NorFlash> Display for FlashOpError<S> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match &self.0 {
+            NorFlashOpError::PartitionError(e) => write!(f, "invalid partition table: {e}"),
+            NorFlashOpError::StorageError(e) => write!(f, "flash error: {e:?}"),
+        }
+    }
+}
+
+impl<S: NorFlash + Debug> core::error::Error for FlashOpError<S> {}
+
+#[cfg(feature = "defmt")]
+impl<S: NorFlash, R: defmt::Format> defmt::Format for OtaUpdateError<S, R> {
+    fn format(&self, f: defmt::Formatter) {
+        match self {
+            OtaUpdateError::PendingVerify => defmt::write!(f, "PendingVerify"),
+            OtaUpdateError::OutOfSpace {
+                partition_size,
+                bytes_written,
+            } => defmt::write!(
+                f,
+                "OutOfSpace {{ partition_size: {}, bytes_written: {} }}",
+                partition_size,
+                bytes_written
+            ),
+            OtaUpdateError::AlreadyUpdating => defmt::write!(f, "AlreadyUpdating"),
+            OtaUpdateError::HashMismatch => defmt::write!(f, "HashMismatch"),
+            OtaUpdateError::InvalidImageMagic => defmt::write!(f, "InvalidImageMagic"),
+            OtaUpdateError::ChecksumMismatch => defmt::write!(f, "ChecksumMismatch"),
+            OtaUpdateError::InvalidAppDescription => defmt::write!(f, "InvalidAppDescription"),
+            OtaUpdateError::DowngradeBlocked {
+                installed,
+                incoming,
+            } => defmt::write!(
+                f,
+                "DowngradeBlocked {{ installed: {}, incoming: {} }}",
+                installed,
+                incoming
+            ),
+            OtaUpdateError::ProjectNameMismatch => defmt::write!(f, "ProjectNameMismatch"),
+            OtaUpdateError::SectorNotErased { offset } => {
+                defmt::write!(f, "SectorNotErased {{ offset: {=u32:#x} }}", offset)
+            }
+            OtaUpdateError::VerifyMismatch { offset } => {
+                defmt::write!(f, "VerifyMismatch {{ offset: {=u32:#x} }}", offset)
+            }
+            OtaUpdateError::UnalignedBuffer { len } => {
+                defmt::write!(f, "UnalignedBuffer {{ len: {} }}", len)
+            }
+            OtaUpdateError::LengthMismatch { expected, actual } => defmt::write!(
+                f,
+                "LengthMismatch {{ expected: {}, actual: {} }}",
+                expected,
+                actual
+            ),
+            OtaUpdateError::ReadTimeout => defmt::write!(f, "ReadTimeout"),
+            OtaUpdateError::SignatureInvalid => defmt::write!(f, "SignatureInvalid"),
+            OtaUpdateError::InvalidResumeOffset { offset } => {
+                defmt::write!(f, "InvalidResumeOffset {{ offset: {=usize:#x} }}", offset)
+            }
+            OtaUpdateError::ImageUnchanged => defmt::write!(f, "ImageUnchanged"),
+            OtaUpdateError::WouldEraseRunningPartition => {
+                defmt::write!(f, "WouldEraseRunningPartition")
+            }
+            OtaUpdateError::ReadError(e) => defmt::write!(f, "ReadError({})", e),
+            OtaUpdateError::InternalError(e) => defmt::write!(f, "InternalError({})", e),
+        }
+    }
+}
+
+/// The wrapped `NorFlashOpError` does not implement `defmt::Format`,
+/// so the partition error is formatted with `Display` and the storage error with `Debug`
+#[cfg(feature = "defmt")]
+impl<S: NorFlash> defmt::Format for OtaInternalError<S> {
+    fn format(&self, f: defmt::Formatter) {
+        match self {
+            OtaInternalError::OtaDataCorrupt => defmt::write!(f, "OtaDataCorrupt"),
+            OtaInternalError::NorFlashOpError(NorFlashOpError::PartitionError(e)) => defmt::write!(
+                f,
+                "NorFlashOpError(PartitionError({}))",
+                defmt::Display2Format(e)
+            ),
+            OtaInternalError::NorFlashOpError(NorFlashOpError::StorageError(e)) => defmt::write!(
+                f,
+                "NorFlashOpError(StorageError({}))",
+                defmt::Debug2Format(e)
+            ),
+            OtaInternalError::PartitionNotFound => defmt::write!(f, "PartitionNotFound"),
+            OtaInternalError::PartitionFoundTwice => defmt::write!(f, "PartitionFoundTwice"),
+            OtaInternalError::AlreadyUpdating => defmt::write!(f, "AlreadyUpdating"),
+            OtaInternalError::OtaDataInconsistent => defmt::write!(f, "OtaDataInconsistent"),
+            OtaInternalError::InvalidAppDescription => defmt::write!(f, "InvalidAppDescription"),
+            OtaInternalError::NothingToRollBackTo => defmt::write!(f, "NothingToRollBackTo"),
+            OtaInternalError::SequenceExhausted => defmt::write!(f, "SequenceExhausted"),
+            OtaInternalError::Misaligned { offset } => {
+                defmt::write!(f, "Misaligned {{ offset: {=u32:#x} }}", offset)
+            }
+            OtaInternalError::OutOfBounds => defmt::write!(f, "OutOfBounds"),
+            OtaInternalError::SequenceMismatch { expected, actual } => defmt::write!(
+                f,
+                "SequenceMismatch {{ expected: {}, actual: {} }}",
+                expected,
+                actual
+            ),
+            OtaInternalError::NoOtaDataPartition => defmt::write!(f, "NoOtaDataPartition"),
+            OtaInternalError::OtaDataTooSmall { size } => {
+                defmt::write!(f, "OtaDataTooSmall {{ size: {} }}", size)
+            }
+            OtaInternalError::TooManyPartitions { count } => {
+                defmt::write!(f, "TooManyPartitions {{ count: {} }}", count)
+            }
+        }
+    }
+}
+
+/// Errors that may occur during an OTA update with an async flash, see `async_flash::ota_begin_async`
+#[cfg(feature = "async-flash")]
+#[derive(Debug)]
+pub enum AsyncOtaUpdateError<E, R> {
+    /// The image that was booted hasn't been verified as working yet,
+    /// so it may not start an update before being verified.
+    /// See `ota_accept`
+    PendingVerify,
+    /// Not enough space in partition. `bytes_written` is the amount of bytes of the `binary` received
+    /// when it no longer fit the `partition_size`, so the image is at least this large
+    OutOfSpace {
+        partition_size: usize,
+        bytes_written: usize,
+    },
+    /// Another update is already in progress
+    AlreadyUpdating,
+    /// The SHA-256 digest appended to the image does not match the image
+    HashMismatch,
+    /// The binary does not start with the magic byte of an ESP image, the boot entry is unchanged
+    InvalidImageMagic,
+    /// The ota data selects the running partition as the target of the update, nothing was erased
+    WouldEraseRunningPartition,
+    /// Read error
+    ReadError(R),
+    /// Internal error while working with the ota partitions
+    InternalError(AsyncOtaInternalError<E>),
+}
+
+#[cfg(feature = "async-flash")]
+impl<E, R> From<AsyncOtaInternalError<E>> for AsyncOtaUpdateError<E, R> {
+    fn from(value: AsyncOtaInternalError<E>) -> Self {
+        AsyncOtaUpdateError::InternalError(value)
+    }
+}
+
+#[cfg(feature = "async-flash")]
+#[derive(Debug)]
+pub enum AsyncOtaInternalError<E> {
+    OtaDataCorrupt,
+    PartitionError(PartitionError),
+    StorageError(E),
+    PartitionNotFound,
+    PartitionFoundTwice,
+    SequenceExhausted,
+    Misaligned { offset: u32 },
+    NoOtaDataPartition,
+    OtaDataTooSmall { size: usize },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::MockFlash;
+    use core::error::Error;
+    use embedded_storage::nor_flash::NorFlashErrorKind;
+    use std::string::ToString;
+
+    #[test]
+    fn flash_error_is_the_source_of_the_internal_error() {
+        let error: OtaUpdateError<MockFlash, ()> =
+            OtaInternalError::NorFlashOpError(NorFlashOpError::StorageError(NorFlashErrorKind::Other)).into();
+        let internal = error.source().unwrap();
+        assert_eq!(internal.to_string(), "flash error: Other");
+        assert_eq!(internal.source().unwrap().to_string(), "flash error: Other");
+        assert!(internal.source().unwrap().source().is_none());
+
+        let not_found: OtaInternalError<MockFlash> = OtaInternalError::PartitionNotFound;
+        assert!(not_found.source().is_none());
+    }
+}
@@ -0,0 +1,374 @@
+use crate::error::OtaInternalError;
+use crate::retry_flash;
+use embedded_storage::nor_flash::NorFlash;
+use esp_partition_table::{NorFlashOpError, PartitionEntry};
+
+/// Magic byte at the start of every ESP app image
+pub(crate) const ESP_IMAGE_MAGIC: u8 = 0xE9;
+
+/// Size of the image header, including the extended header
+pub(crate) const IMAGE_HEADER_SIZE: usize = 24;
+
+/// Size of the header in front of every segment
+const SEGMENT_HEADER_SIZE: usize = 8;
+
+/// Size of the SHA-256 digest esptool appends when `hash_appended` is set
+pub(crate) const HASH_SIZE: usize = 32;
+
+/// Maximum amount of segments the bootloader accepts (`ESP_IMAGE_MAX_SEGMENTS`)
+const MAX_SEGMENTS: u8 = 16;
+
+/// Offset of the app description (`esp_app_desc_t`) in the image, at the start of the first segment
+const APP_DESC_OFFSET: usize = IMAGE_HEADER_SIZE + SEGMENT_HEADER_SIZE;
+
+/// Magic word at the start of the app description
+const APP_DESC_MAGIC: u32 = 0xABCD5432;
+
+/// Returns true if the partition starts with a plausible image header
+pub(crate) fn partition_has_image<S: NorFlash>(
+    storage: &mut S,
+    partition: &PartitionEntry,
+) -> Result<bool, OtaInternalError<S>> {
+    let mut header = [0; 4];
+    retry_flash(|| storage.read(partition.offset, &mut header))
+        .map_err(|e| NorFlashOpError::StorageError(e))?;
+    Ok(header[0] == ESP_IMAGE_MAGIC && header[1] <= MAX_SEGMENTS)
+}
+
+/// Determine the size of the image in a partition by walking its segment headers.
+/// The size includes the appended digest if there is one.
+/// Returns `None` if the partition doesn't contain a valid image header or the segments don't fit the partition.
+pub(crate) fn read_image_len<S: NorFlash>(
+    storage: &mut S,
+    partition: &PartitionEntry,
+) -> Result<Option<usize>, OtaInternalError<S>> {
+    let mut header = [0; IMAGE_HEADER_SIZE];
+    retry_flash(|| storage.read(partition.offset, &mut header))
+        .map_err(|e| NorFlashOpError::StorageError(e))?;
+    if header[0] != ESP_IMAGE_MAGIC || header[1] > MAX_SEGMENTS {
+        return Ok(None);
+    }
+
+    let mut position = IMAGE_HEADER_SIZE;
+    for _ in 0..header[1] {
+        if position + SEGMENT_HEADER_SIZE > partition.size {
+            return Ok(None);
+        }
+        let mut segment_header = [0; SEGMENT_HEADER_SIZE];
+        retry_flash(|| storage.read(partition.offset + position as u32, &mut segment_header))
+            .map_err(|e| NorFlashOpError::StorageError(e))?;
+        let len = u32::from_le_bytes(segment_header[4..8].try_into().unwrap());
+        position = match (position + SEGMENT_HEADER_SIZE).checked_add(len as usize) {
+            Some(end) if end <= partition.size => end,
+            _ => return Ok(None),
+        };
+    }
+
+    // The checksum byte is placed such that the image ends on a 16 byte boundary
+    let mut image_len = (position | 0xF) + 1;
+    if header[23] == 1 {
+        image_len += HASH_SIZE;
+    }
+    Ok((image_len <= partition.size).then_some(image_len))
+}
+
+/// Size of the app description
+const APP_DESC_SIZE: usize = 256;
+
+/// The app description (`esp_app_desc_t`) that esp-idf embeds in every app image
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AppDescription {
+    /// Version used by the anti-rollback feature of the bootloader
+    pub secure_version: u32,
+    version: [u8; 32],
+    project_name: [u8; 32],
+    time: [u8; 16],
+    date: [u8; 16],
+    idf_ver: [u8; 32],
+    app_elf_sha256: [u8; 32],
+}
+
+impl AppDescription {
+    /// Parse the app description from the start of an image.
+    /// Returns `None` if the image does not start with a valid app description.
+    pub(crate) fn from_image(image: &[u8]) -> Option<Self> {
+        let desc = image.get(APP_DESC_OFFSET..APP_DESC_OFFSET + APP_DESC_SIZE)?;
+        if image[0] != ESP_IMAGE_MAGIC
+            || u32::from_le_bytes(desc[0..4].try_into().unwrap()) != APP_DESC_MAGIC
+        {
+            return None;
+        }
+
+        Some(Self {
+            secure_version: u32::from_le_bytes(desc[4..8].try_into().unwrap()),
+            version: desc[16..48].try_into().unwrap(),
+            project_name: desc[48..80].try_into().unwrap(),
+            time: desc[80..96].try_into().unwrap(),
+            date: desc[96..112].try_into().unwrap(),
+            idf_ver: desc[112..144].try_into().unwrap(),
+            app_elf_sha256: desc[144..176].try_into().unwrap(),
+        })
+    }
+
+    /// Read the app description of the image at a flash offset
+    pub(crate) fn read<S: NorFlash>(
+        storage: &mut S,
+        offset: u32,
+    ) -> Result<Self, OtaInternalError<S>> {
+        let mut buffer = [0; APP_DESC_OFFSET + APP_DESC_SIZE];
+        retry_flash(|| storage.read(offset, &mut buffer))
+            .map_err(|e| NorFlashOpError::StorageError(e))?;
+        Self::from_image(&buffer).ok_or(OtaInternalError::InvalidAppDescription)
+    }
+
+    /// Version of the app, i.e. `PROJECT_VER` or the output of `git describe`
+    pub fn version(&self) -> &str {
+        c_str(&self.version)
+    }
+
+    /// Name of the project
+    pub fn project_name(&self) -> &str {
+        c_str(&self.project_name)
+    }
+
+    /// Compile time of the app
+    pub fn time(&self) -> &str {
+        c_str(&self.time)
+    }
+
+    /// Compile date of the app
+    pub fn date(&self) -> &str {
+        c_str(&self.date)
+    }
+
+    /// Version of esp-idf the app was built with
+    pub fn idf_ver(&self) -> &str {
+        c_str(&self.idf_ver)
+    }
+
+    /// SHA-256 digest of the ELF file the app was built from, which identifies the exact build
+    pub fn app_elf_sha256(&self) -> &[u8; 32] {
+        &self.app_elf_sha256
+    }
+}
+
+/// Interpret a zero-terminated fixed size field as a string, ignoring anything that isn't valid UTF-8
+fn c_str(bytes: &[u8]) -> &str {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    match core::str::from_utf8(&bytes[..len]) {
+        Ok(s) => s,
+        Err(e) => core::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap(),
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum ParserState {
+    Header,
+    SegmentHeader,
+    SegmentData,
+    Done,
+    Invalid,
+}
+
+/// Incremental parser for the ESP image layout.
+/// The image is fed in arbitrarily sized chunks, in order, as it is streamed.
+///
+/// Layout as produced by esptool:
+/// - 24 byte image header, byte 0 is the magic, byte 1 the segment count, byte 23 `hash_appended`
+/// - per segment an 8 byte header (load address, data length) followed by the data
+/// - padding up to a 16 byte boundary, of which the last byte is the checksum
+/// - if `hash_appended` is set, the SHA-256 of all the above
+#[derive(Debug, Clone)]
+pub(crate) struct ImageParser {
+    /// Amount of bytes consumed so far
+    position: usize,
+    /// Position at which the structure that is currently being parsed ends
+    next: usize,
+    header: [u8; IMAGE_HEADER_SIZE],
+    segment_header: [u8; SEGMENT_HEADER_SIZE],
+    segment: u8,
+    state: ParserState,
+    image_len: Option<usize>,
+}
+
+impl ImageParser {
+    pub(crate) fn new() -> Self {
+        Self {
+            position: 0,
+            next: IMAGE_HEADER_SIZE,
+            header: [0; IMAGE_HEADER_SIZE],
+            segment_header: [0; SEGMENT_HEADER_SIZE],
+            segment: 0,
+            state: ParserState::Header,
+            image_len: None,
+        }
+    }
+
+    /// Feed the next chunk of the image to the parser
+    pub(crate) fn feed(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if matches!(self.state, ParserState::Done | ParserState::Invalid) {
+                self.position += data.len();
+                return;
+            }
+
+            let take = data.len().min(self.next - self.position);
+            let (chunk, rest) = data.split_at(take);
+            match self.state {
+                ParserState::Header => {
+                    let start = self.position;
+                    self.header[start..start + take].copy_from_slice(chunk);
+                }
+                ParserState::SegmentHeader => {
+                    let start = SEGMENT_HEADER_SIZE - (self.next - self.position);
+                    self.segment_header[start..start + take].copy_from_slice(chunk);
+                }
+                _ => {}
+            }
+            self.position += take;
+            data = rest;
+
+            if self.position == self.next {
+                self.finish_structure();
+            }
+        }
+    }
+
+    /// Called when the structure that was being parsed has been fully consumed
+    fn finish_structure(&mut self) {
+        match self.state {
+            ParserState::Header => {
+                if self.header[0] != ESP_IMAGE_MAGIC || self.header[1] > MAX_SEGMENTS {
+                    self.state = ParserState::Invalid;
+                } else {
+                    self.begin_segment();
+                }
+            }
+            ParserState::SegmentHeader => {
+                let len = u32::from_le_bytes(self.segment_header[4..8].try_into().unwrap());
+                let Some(next) = self.position.checked_add(len as usize) else {
+                    self.state = ParserState::Invalid;
+                    return;
+                };
+                self.state = ParserState::SegmentData;
+                self.next = next;
+                if len == 0 {
+                    self.finish_structure();
+                }
+            }
+            ParserState::SegmentData => {
+                self.segment += 1;
+                self.begin_segment();
+            }
+            ParserState::Done | ParserState::Invalid => {}
+        }
+    }
+
+    fn begin_segment(&mut self) {
+        if self.segment == self.header[1] {
+            // The checksum byte is placed such that the image ends on a 16 byte boundary
+            self.image_len = (self.position | 0xF).checked_add(1);
+            self.state = match self.image_len {
+                Some(_) => ParserState::Done,
+                None => ParserState::Invalid,
+            };
+        } else {
+            match self.position.checked_add(SEGMENT_HEADER_SIZE) {
+                Some(next) => {
+                    self.state = ParserState::SegmentHeader;
+                    self.next = next;
+                }
+                None => self.state = ParserState::Invalid,
+            }
+        }
+    }
+
+    /// Returns true if the full image header has been received and it is not a valid ESP image header,
+    /// or if the segment headers describe an image larger than the address space
+    pub(crate) fn is_invalid(&self) -> bool {
+        self.state == ParserState::Invalid
+    }
+
+    /// Returns true if the header indicates a SHA-256 digest is appended to the image
+    pub(crate) fn hash_appended(&self) -> bool {
+        self.position >= IMAGE_HEADER_SIZE && !self.is_invalid() && self.header[23] == 1
+    }
+
+    /// Length of the image up to and including the checksum byte, excluding the appended digest.
+    /// Only known once all segment headers have been parsed.
+    pub(crate) fn image_len(&self) -> Option<usize> {
+        self.image_len
+    }
+
+    /// Lower bound of the length of the image including the appended digest, from the headers parsed so far:
+    /// the image extends at least up to the end of the structure that is currently being parsed.
+    /// Only meaningful if the image is not invalid.
+    pub(crate) fn min_image_len(&self) -> usize {
+        match self.image_len() {
+            Some(len) if self.hash_appended() => len + HASH_SIZE,
+            Some(len) => len,
+            None => self.next,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn parser_finds_the_image_length_in_any_chunk_size() {
+        let image = image(1000);
+        for chunk in [1, 7, 24, 1000, image.len()] {
+            let mut parser = ImageParser::new();
+            for data in image.chunks(chunk) {
+                parser.feed(data);
+            }
+            assert_eq!(parser.image_len(), Some(image.len()), "chunk size {chunk}");
+        }
+    }
+
+    #[test]
+    fn segment_longer_than_the_partition_has_no_image_length() {
+        let mut image = image(1000);
+        image[28..32].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut flash = MockFlash::new(2);
+        flash.set_slot(0, &image);
+        let partition = crate::partitions::find_partition_by_type(
+            &mut flash,
+            esp_partition_table::PartitionType::App(esp_partition_table::AppPartitionType::Ota(0)),
+        )
+        .unwrap();
+        assert_eq!(read_image_len(&mut flash, &partition).unwrap(), None);
+
+        let mut parser = ImageParser::new();
+        parser.feed(&image);
+        assert_eq!(parser.image_len(), None);
+        assert!(parser.min_image_len() > APP_SIZE);
+    }
+
+    #[test]
+    fn failed_image_reads_are_retried() {
+        let _serial = serial();
+        let image = image(1000);
+        let mut flash = MockFlash::new(2);
+        flash.set_slot(0, &image);
+        let partition = crate::partitions::find_partition_by_type(
+            &mut flash,
+            esp_partition_table::PartitionType::App(esp_partition_table::AppPartitionType::Ota(0)),
+        )
+        .unwrap();
+        flash.failing_reads = 1;
+        assert!(read_image_len(&mut flash, &partition).is_err());
+
+        crate::set_flash_retries(1);
+        flash.failing_reads = 1;
+        let image_len = read_image_len(&mut flash, &partition);
+        flash.failing_reads = 1;
+        let has_image = partition_has_image(&mut flash, &partition);
+        crate::set_flash_retries(0);
+        assert_eq!(image_len.unwrap(), Some(image.len()));
+        assert!(has_image.unwrap());
+    }
+}
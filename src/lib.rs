@@ -1,71 +1,700 @@
 #![no_std]
 
+#[cfg(test)]
+extern crate std;
+
+#[macro_use]
+mod fmt;
+
+#[cfg(feature = "async-flash")]
+pub mod async_flash;
+mod blocking;
+mod builder;
 mod crc;
 mod error;
+mod image;
 mod ota_data;
 mod ota_data_structs;
 pub mod partitions;
+pub mod recovery;
+mod store;
+#[cfg(test)]
+mod test_utils;
+mod updater;
+#[cfg(feature = "sha256")]
+mod verify;
+mod writer;
 
 use crate::error::{OtaInternalError, OtaUpdateError};
-use crate::ota_data::{read_ota_data, write_ota_data};
-use crate::ota_data_structs::{EspOTAData, EspOTAState};
+use crate::image::{ImageParser, ESP_IMAGE_MAGIC};
+use crate::ota_data::{read_ota_data, write_ota_data, write_pending_ota_data};
+use core::fmt::Debug;
+use core::future::Future;
 use core::sync::atomic::Ordering;
+use core::task::Poll;
 use embedded_io_async::Read;
-use embedded_storage::nor_flash::NorFlash;
+use embedded_storage::nor_flash::{MultiwriteNorFlash, NorFlash};
 use esp_partition_table::{AppPartitionType, NorFlashOpError, PartitionEntry, PartitionType};
-use portable_atomic::AtomicBool;
-use crate::partitions::find_partition_by_type;
+use portable_atomic::{AtomicBool, AtomicU8};
+#[cfg(feature = "sha256")]
+use sha2::Digest;
+use crate::partitions::{OtaLayout, ParsedPartitions, MAX_OTA_PARTITIONS};
+pub use crate::blocking::{ota_begin_blocking, ota_begin_blocking_with_options, ota_begin_from_slice};
+pub use crate::builder::OtaBuilder;
+pub use crate::crc::ota_data_crc;
+pub use crate::image::AppDescription;
+pub use crate::ota_data::{set_transition_hook, OtaTransition};
+pub use crate::ota_data_structs::{EspOTAData, EspOTAState, OtaDataError};
+pub use crate::updater::OtaUpdater;
+pub use crate::writer::{ota_begin_chunked, ota_begin_chunked_with_layout, OtaWriter};
+#[cfg(feature = "sha256")]
+pub use crate::verify::{IncrementalVerifier, VerifyStatus};
 
-/// Size of a flash sector
+/// Size of a sector in the ESP partition format, the two copies of the ota data are this far apart.
+/// The ota data can therefore only be stored on flash with an `ERASE_SIZE` of at most this size,
+/// otherwise erasing one copy would also erase the other.
+/// Images are also streamed in chunks of this size, which is independent of the erase size since writes only need
+/// to be aligned to `WRITE_SIZE`. Anything that erases app or data partitions uses `S::ERASE_SIZE` instead.
 const SECTOR_SIZE: usize = 0x1000;
 
 static IS_UPDATING: AtomicBool = AtomicBool::new(false);
 
+/// Marks an update as in progress while it is alive.
+/// The flag is released when the guard is dropped, so also when the update fails or its future is dropped.
+struct UpdateGuard;
+
+impl UpdateGuard {
+    /// Returns `None` if another update is already in progress
+    fn acquire() -> Option<Self> {
+        (!IS_UPDATING.swap(true, Ordering::SeqCst)).then_some(UpdateGuard)
+    }
+}
+
+impl Drop for UpdateGuard {
+    fn drop(&mut self) {
+        IS_UPDATING.store(false, Ordering::SeqCst);
+    }
+}
+
+static FLASH_RETRIES: AtomicU8 = AtomicU8::new(0);
+
+/// Retry flash operations that fail up to `retries` times before returning the error, for flash that occasionally
+/// fails transiently, e.g. an external SPI flash. Applies to the erases, writes and reads of this crate, including
+/// those of `async_flash`, except for reading the partition table entries in the blocking functions,
+/// which `esp-partition-table` does itself. Defaults to 0, i.e. errors are returned immediately.
+/// Retrying is safe since these operations are idempotent: erasing again gives the same result,
+/// and writing the same data again can only clear the bits that should have been cleared.
+pub fn set_flash_retries(retries: u8) {
+    FLASH_RETRIES.store(retries, Ordering::SeqCst);
+}
+
+/// Overrides the `set_flash_retries` setting while it is alive, see `OtaOptions::flash_retries`.
+/// Restoring on drop also covers an update future that is dropped before it completes.
+struct RetriesOverride(Option<u8>);
+
+impl RetriesOverride {
+    fn new(retries: Option<u8>) -> Self {
+        Self(retries.map(|retries| FLASH_RETRIES.swap(retries, Ordering::SeqCst)))
+    }
+}
+
+impl Drop for RetriesOverride {
+    fn drop(&mut self) {
+        if let Some(previous) = self.0 {
+            FLASH_RETRIES.store(previous, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Run a flash operation, retrying it as configured with `set_flash_retries`
+pub(crate) fn retry_flash<T, E>(mut op: impl FnMut() -> Result<T, E>) -> Result<T, E> {
+    let mut retries = FLASH_RETRIES.load(Ordering::SeqCst);
+    loop {
+        match op() {
+            Err(_) if retries > 0 => {
+                warn!("Flash operation failed, retrying ({retries} retries left)");
+                retries -= 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Returns true if an update is marked as in progress, i.e. functions return `AlreadyUpdating`.
+/// Has no side effects, intended for diagnostics.
+pub fn is_update_in_progress() -> bool {
+    IS_UPDATING.load(Ordering::SeqCst)
+}
+
+/// Clear the in-progress flag, so a new update may be started after an earlier one got stuck,
+/// i.e. its future is never polled again but also never dropped (failed updates release the flag themselves).
+/// Only call this when no update is actually running, otherwise two updates may write to the same partition.
+pub fn ota_force_unlock() {
+    warn!("Forcefully clearing the update in progress flag");
+    IS_UPDATING.store(false, Ordering::SeqCst);
+}
+
+/// Optional behaviour of an OTA update, see `ota_begin_with_options`
+#[derive(Debug, Clone, Default)]
+pub struct OtaOptions<'a> {
+    /// Name of a data partition used to persist the highest `secure_version` that was ever installed.
+    /// Images with a lower `secure_version` in their app description are rejected with `DowngradeBlocked`,
+    /// the stored version is raised after each successful update.
+    /// See `ota_clear_version_store` to reset it.
+    pub version_store: Option<&'a str>,
+    /// Reject images with a lower `secure_version` than the running app with `DowngradeBlocked`.
+    /// Unlike `version_store` this needs no extra partition, but it only protects against downgrading the running app,
+    /// not against downgrading to a version that was installed before it.
+    /// The check is done on the first chunk of the `binary`, by then the partition has already been erased.
+    pub reject_downgrade: bool,
+    /// Where to find the partitions used for OTA
+    pub layout: OtaLayout<'a>,
+    /// Check that each sector is erased before writing it, by reading its first word.
+    /// Catches flash where the erase silently failed, without the cost of reading back the full image.
+    pub check_erased: bool,
+    /// Only accept images whose app description has this project name, rejecting others with `ProjectNameMismatch`.
+    /// Prevents flashing the firmware of another product that shares the same update server.
+    pub expected_project_name: Option<&'a str>,
+    /// Erase the partition in chunks of at most this many sectors of `S::ERASE_SIZE` bytes, yielding to the executor in between.
+    /// This bounds the time spent in a single erase call, so e.g. a watchdog can be fed during the erase.
+    /// By default the partition is erased in a single call.
+    pub max_erase_chunk_sectors: Option<usize>,
+    /// Read back the first sector (the first buffer, see `ota_begin_with_buffer`) right after writing it and abort with `VerifyMismatch` if it differs.
+    /// The first sector contains the image header, so this fails fast on the most common fatal write failure
+    /// before time is spent on the rest of the image.
+    pub verify_first_sector: bool,
+    /// Read back every sector right after writing it and abort with `VerifyMismatch` if it differs,
+    /// so a sector that silently failed to program is caught before the update is marked as bootable.
+    /// This roughly doubles the time spent on flash access.
+    pub verify_written: bool,
+    /// Value erased flash reads as, used by `check_erased`. Defaults to `0xFF` as on NOR flash,
+    /// other values are only useful for storage backends used in simulation.
+    /// The ota data and the `version_store` always use `0xFF` for unwritten fields, like the bootloader does.
+    pub erased_byte: Option<u8>,
+    /// Expected SHA-256 digest of the full `binary`, e.g. from a `.sha256` file distributed next to it.
+    /// The digest is computed while streaming, the update fails with `ChecksumMismatch` before it is marked as bootable.
+    /// This is independent of the digest that esptool may append to the image, and covers any padding after the image.
+    #[cfg(feature = "sha256")]
+    pub expected_sha256: Option<[u8; 32]>,
+    /// Fail with `HashMismatch` if the image does not have a SHA-256 digest appended by esptool,
+    /// instead of only verifying the digest when there is one.
+    #[cfg(feature = "sha256")]
+    pub require_appended_hash: bool,
+    /// Compute the SHA-256 digest of the full `binary` while streaming and return it in `OtaOutcome::sha256`,
+    /// e.g. so the device can report back what it wrote to the server. This works for any image,
+    /// also those without an appended digest, and is computed in the same pass as `expected_sha256`.
+    #[cfg(feature = "sha256")]
+    pub report_sha256: bool,
+    /// Length of the image, if it is known upfront, e.g. from a `Content-Length` header.
+    /// The length is checked against the partition size before anything is erased,
+    /// and the update fails with `LengthMismatch` if the `binary` turns out to have a different length.
+    pub expected_len: Option<usize>,
+    /// Called once when the update enters each phase, in the order of the `OtaPhase` variants,
+    /// e.g. to change a status text at the moment the erase finishes and writing begins.
+    pub on_phase_change: Option<fn(OtaPhase)>,
+    /// Continue an interrupted update from this offset in the image, see `ota_resume`
+    pub resume_offset: Option<usize>,
+    /// Compare the `binary` with the running image while streaming, and fail with `ImageUnchanged` instead of
+    /// bumping the sequence number if it is identical, e.g. when the server doesn't deduplicate by version.
+    /// Nothing is erased or written until the `binary` differs, so an unchanged image costs no flash wear.
+    /// The `binary` is compared byte by byte with the flash, so this doesn't need the `sha256` feature.
+    /// `OtaPhase::Erasing` is entered when the first difference is found, i.e. after `OtaPhase::Writing`.
+    pub skip_if_identical: bool,
+    /// The 20 byte `seq_label` field to write in the new boot entry, see `EspOTAData::label`.
+    /// Defaults to all `0xFF`, which is what esp-idf writes. The esp-idf bootloader doesn't read this field,
+    /// also not with secure boot or anti-rollback, which use the `secure_version` of the app description instead,
+    /// so `0xFF` is always accepted. Only set it for a custom bootloader or tooling that expects an identifier here.
+    pub label: Option<[u8; 20]>,
+    /// Retry failed flash operations this many times during this update, instead of the setting of `set_flash_retries`.
+    /// The previous setting is restored when the update is done, also if the update future is dropped.
+    /// Since the setting is global, an `OtaUpdater` takes the global in-progress flag for an update that sets this.
+    pub flash_retries: Option<u8>,
+}
+
+/// Phase of an OTA update, see `OtaOptions::on_phase_change`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum OtaPhase {
+    /// The target partition is being erased
+    Erasing,
+    /// The image is being read from the `binary` and written to flash
+    Writing,
+    /// The written image is being verified against the configured digests
+    Verifying,
+    /// The new boot entry is being written
+    Finalizing,
+}
+
+/// Result of a successful OTA update
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct OtaOutcome {
+    /// The exact size of the image that was flashed, i.e. the amount of bytes read from the `binary`.
+    /// This is never larger than the partition, streams that don't fit fail with `OutOfSpace`.
+    pub bytes_written: usize,
+    /// The SHA-256 digest of the `binary`, if requested with `OtaOptions::report_sha256`
+    #[cfg(feature = "sha256")]
+    pub sha256: Option<[u8; 32]>,
+}
+
+/// Timing of a successful OTA update, see `ota_begin_with_clock`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct OtaStats {
+    /// The exact size of the image that was flashed, see `OtaOutcome::bytes_written`
+    pub bytes_written: usize,
+    /// The time spent erasing and writing the app partition, in ticks of the clock.
+    /// The time spent waiting for the `binary` is not included.
+    pub write_duration_ticks: u64,
+}
+
 /// Starts a new OTA update.
 /// - The `binary` is the data that should be written to the ota partition.
 /// - This function returns an error if multiple ota updates are attempted concurrently.
 /// - If the update was successful, the caller should reboot to activate the new firmware.
+/// - The new boot entry only becomes bootable once the image has been fully written and verified,
+///   if the update is interrupted before that the old firmware is booted.
+/// - The image must fit in a single ota app partition, otherwise `OutOfSpace` is returned.
+///   Spilling into an adjacent partition is not supported, since the bootloader rejects images that extend past their partition.
 /// - The `progress_fn` is called periodically with the total amount of bytes written so far.
+/// - On success, returns how many bytes were read from the `binary` and flashed.
+/// - With the `sha256` feature, an image with an appended SHA-256 digest is verified before it is marked as bootable.
+///   The digest is computed while streaming and the appended digest is taken from the tail of the stream,
+///   so the `binary` is read only once and does not need to be seekable.
+///   The length of the image is taken from its segment headers, so padding after the digest
+///   (e.g. `0xFF` or `0x00` bytes added by a server to reach a flash boundary) is flashed but does not affect the verification.
+/// - Each sector is read from the `binary` and then written with the blocking `NorFlash::write`.
+///   Reading the next sector can't overlap that write, network stacks that receive into their own buffers
+///   keep receiving in the background, so a larger receive buffer is the way to increase throughput.
 pub async fn ota_begin<S: NorFlash, R: Read>(
     storage: &mut S,
-    mut binary: R,
+    binary: R,
+    progress_fn: impl FnMut(usize),
+) -> Result<OtaOutcome, OtaUpdateError<S, R::Error>> {
+    ota_begin_with_options(storage, binary, progress_fn, OtaOptions::default()).await
+}
+
+/// Starts a new OTA update of `total` bytes, like `ota_begin` but for when the length is known upfront,
+/// e.g. from a `Content-Length` header.
+/// - The `progress_fn` is called with the amount of bytes written so far and the `total`, so a percentage can be shown.
+/// - Fails with `OutOfSpace` before the partition is erased if `total` doesn't fit,
+///   and with `LengthMismatch` if the `binary` turns out to have a different length, see `OtaOptions::expected_len`.
+pub async fn ota_begin_with_len<S: NorFlash, R: Read>(
+    storage: &mut S,
+    binary: R,
+    total: usize,
+    mut progress_fn: impl FnMut(usize, usize),
+) -> Result<OtaOutcome, OtaUpdateError<S, R::Error>> {
+    let options = OtaOptions {
+        expected_len: Some(total),
+        ..OtaOptions::default()
+    };
+    ota_begin_with_options(storage, binary, |written| progress_fn(written, total), options).await
+}
+
+/// Starts a new OTA update, like `ota_begin` but with a `progress_fn` that returns a future,
+/// which is awaited after each sector is written, e.g. to send a progress message or to yield to other tasks.
+/// Like the update itself, the progress future may be cancelled by dropping the update,
+/// which releases the update in progress flag.
+pub async fn ota_begin_with_async_progress<S: NorFlash, R: Read, F: Future<Output = ()>>(
+    storage: &mut S,
+    binary: R,
+    mut progress_fn: impl FnMut(usize) -> F,
+) -> Result<OtaOutcome, OtaUpdateError<S, R::Error>> {
+    ota_begin_with_hooks(storage, binary, async |written| progress_fn(written).await, OtaOptions::default(), &mut NoHooks).await
+}
+
+/// Starts a new OTA update, like `ota_begin_with_options` but failing with `ReadTimeout` if a single read of the `binary` doesn't
+/// complete before the future returned by `read_timeout` does, e.g. because the transport stalled without an error.
+/// Since this crate doesn't depend on an executor, the caller supplies the timer,
+/// e.g. `|| embassy_time::Timer::after_secs(10)`. A new timer is created for every read.
+pub async fn ota_begin_with_timeout<S: NorFlash, R: Read, F: Future<Output = ()>>(
+    storage: &mut S,
+    binary: R,
     mut progress_fn: impl FnMut(usize),
-) -> Result<(), OtaUpdateError<S, R::Error>> {
+    options: OtaOptions<'_>,
+    read_timeout: impl FnMut() -> F,
+) -> Result<OtaOutcome, OtaUpdateError<S, R::Error>> {
+    let mut hooks = ReadTimeoutHook(read_timeout);
+    ota_begin_with_hooks(storage, binary, async |written| progress_fn(written), options, &mut hooks).await
+}
+
+/// Starts a new OTA update, like `ota_begin_with_options` but passing every buffer to `transform` right before it is written,
+/// e.g. to encrypt the image for a device with flash encryption enabled, which this crate doesn't implement itself.
+/// - The `transform` is called with the absolute flash offset the buffer is written to, which e.g. the AES-XTS tweak depends on,
+///   and a buffer of at most a sector that is a multiple of the flash's `WRITE_SIZE`, including the padding of the last chunk.
+/// - The image checks and digests are done on the untransformed `binary`,
+///   while the transformed data is what `OtaOptions::verify_written` compares the flash with.
+pub async fn ota_begin_with_transform<S: NorFlash, R: Read>(
+    storage: &mut S,
+    binary: R,
+    mut progress_fn: impl FnMut(usize),
+    options: OtaOptions<'_>,
+    transform: impl FnMut(u32, &mut [u8]),
+) -> Result<OtaOutcome, OtaUpdateError<S, R::Error>> {
+    let mut hooks = TransformHook(transform);
+    ota_begin_with_hooks(storage, binary, async |written| progress_fn(written), options, &mut hooks).await
+}
+
+/// Starts a new OTA update, like `ota_begin` but only accepting images with a SHA-256 digest appended by esptool
+/// (the default for esp-idf builds), see `OtaOptions::require_appended_hash`.
+/// A corrupted or truncated download, or an image without digest, fails with `HashMismatch` before it is marked as bootable.
+#[cfg(feature = "sha256")]
+pub async fn ota_begin_checked<S: NorFlash, R: Read>(
+    storage: &mut S,
+    binary: R,
+    progress_fn: impl FnMut(usize),
+) -> Result<OtaOutcome, OtaUpdateError<S, R::Error>> {
+    let options = OtaOptions {
+        require_appended_hash: true,
+        ..OtaOptions::default()
+    };
+    ota_begin_with_options(storage, binary, progress_fn, options).await
+}
+
+/// Starts a new OTA update, like `ota_begin` but rejecting images with a lower `secure_version` than the running app
+/// with `DowngradeBlocked`, see `OtaOptions::reject_downgrade`.
+pub async fn ota_begin_no_downgrade<S: NorFlash, R: Read>(
+    storage: &mut S,
+    binary: R,
+    progress_fn: impl FnMut(usize),
+) -> Result<OtaOutcome, OtaUpdateError<S, R::Error>> {
+    let options = OtaOptions {
+        reject_downgrade: true,
+        ..OtaOptions::default()
+    };
+    ota_begin_with_options(storage, binary, progress_fn, options).await
+}
+
+/// Starts a new OTA update, like `ota_begin` but with the additional behaviour configured in `options`.
+pub async fn ota_begin_with_options<S: NorFlash, R: Read>(
+    storage: &mut S,
+    binary: R,
+    progress_fn: impl FnMut(usize),
+    options: OtaOptions<'_>,
+) -> Result<OtaOutcome, OtaUpdateError<S, R::Error>> {
+    let mut buffer = [0; SECTOR_SIZE];
+    ota_begin_with_buffer(storage, binary, progress_fn, options, &mut buffer).await
+}
+
+/// Starts a new OTA update, like `ota_begin_with_options` but reading the `binary` into a caller-provided `buffer`
+/// instead of a 4 KiB buffer on the stack, e.g. so it can be placed in a static on a task with a small stack.
+/// - The `buffer` must be a non-empty multiple of the flash's `WRITE_SIZE`, otherwise `UnalignedBuffer` is returned.
+///   It does not need to be a full sector.
+/// - The image is written in chunks of the buffer size, so a smaller buffer means more read and write calls.
+///   Buffers smaller than a sector will slow down the update.
+/// - If the `version_store` or `expected_project_name` options are used, the buffer must be at least 288 bytes,
+///   so the app description is contained in the first chunk.
+pub async fn ota_begin_with_buffer<S: NorFlash, R: Read>(
+    storage: &mut S,
+    binary: R,
+    mut progress_fn: impl FnMut(usize),
+    options: OtaOptions<'_>,
+    data_buffer: &mut [u8],
+) -> Result<OtaOutcome, OtaUpdateError<S, R::Error>> {
+    if data_buffer.is_empty() || !data_buffer.len().is_multiple_of(S::WRITE_SIZE) {
+        return Err(OtaUpdateError::UnalignedBuffer {
+            len: data_buffer.len(),
+        });
+    }
+
+    // Check if there is already an update happening
+    let Some(_guard) = UpdateGuard::acquire() else {
+        return Err(OtaUpdateError::AlreadyUpdating);
+    };
+
+    let progress_fn = async |written| progress_fn(written);
+    ota_begin_unguarded(storage, binary, progress_fn, options, data_buffer, &mut NoHooks).await
+}
+
+/// Starts a new OTA update, like `ota_begin_with_options` but calling `verify` after the image is fully written and passed the other checks,
+/// before the new boot entry is written, e.g. to verify the secure boot v2 signature block, which this crate doesn't implement itself.
+/// `verify` is passed the flash and the partition the image was written to, so it can read the image back.
+/// If it returns an error the update fails with `SignatureInvalid` and the old firmware keeps booting.
+pub async fn ota_begin_signed<S: NorFlash, R: Read, E: Debug>(
+    storage: &mut S,
+    binary: R,
+    mut progress_fn: impl FnMut(usize),
+    options: OtaOptions<'_>,
+    verify: impl FnOnce(&mut S, &PartitionEntry) -> Result<(), E>,
+) -> Result<OtaOutcome, OtaUpdateError<S, R::Error>> {
+    let mut hooks = SignatureHook(Some(verify));
+    ota_begin_with_hooks(storage, binary, async |written| progress_fn(written), options, &mut hooks).await
+}
+
+/// Continue an update that was interrupted after `resume_offset` bytes of the image were written,
+/// e.g. with an HTTP range request after the connection dropped, instead of erasing and downloading the full image again.
+/// - The `binary` must start at byte `resume_offset` of the image, which the caller is responsible for.
+/// - The `resume_offset` must be a multiple of the flash's `ERASE_SIZE` and at most the partition size,
+///   otherwise `InvalidResumeOffset` is returned. Only the partition after it is erased.
+/// - The update must target the same partition as the interrupted one, which is the case as long as no other
+///   update was committed in between and the running app is the same. The part that was already written
+///   is read back from flash, so the image is still verified as a whole.
+/// - The `progress_fn` is called with the total amount of bytes written, including the `resume_offset`.
+pub async fn ota_resume<S: NorFlash, R: Read>(
+    storage: &mut S,
+    binary: R,
+    resume_offset: usize,
+    progress_fn: impl FnMut(usize),
+) -> Result<OtaOutcome, OtaUpdateError<S, R::Error>> {
+    let options = OtaOptions {
+        resume_offset: Some(resume_offset),
+        ..OtaOptions::default()
+    };
+    ota_begin_with_options(storage, binary, progress_fn, options).await
+}
+
+/// Starts a new OTA update, like `ota_begin_with_options` but for a flash that may be written multiple times without erasing.
+/// The update is committed by writing over its pending entry without erasing that copy of the ota data first,
+/// see `write_ota_data_multiwrite`, so a power loss during the commit can't leave that copy blank.
+pub async fn ota_begin_multiwrite<S: MultiwriteNorFlash, R: Read>(
+    storage: &mut S,
+    binary: R,
+    mut progress_fn: impl FnMut(usize),
+    options: OtaOptions<'_>,
+) -> Result<OtaOutcome, OtaUpdateError<S, R::Error>> {
+    ota_begin_with_hooks(storage, binary, async |written| progress_fn(written), options, &mut MultiwriteHook).await
+}
+
+/// Starts a new OTA update, like `ota_begin_with_options` but also measuring how long the flash takes,
+/// e.g. to tell a slow flash apart from a slow network when an update takes too long.
+/// - The `clock` returns a monotonic tick count in any unit, e.g. `|| Instant::now().as_ticks()` with embassy-time.
+///   It is only sampled right before and after each erase and write of the app partition.
+/// - The throughput of the flash is `bytes_written / write_duration_ticks`.
+pub async fn ota_begin_with_clock<S: NorFlash, R: Read>(
+    storage: &mut S,
+    binary: R,
+    mut progress_fn: impl FnMut(usize),
+    options: OtaOptions<'_>,
+    clock: impl FnMut() -> u64,
+) -> Result<OtaStats, OtaUpdateError<S, R::Error>> {
+    let mut hooks = ClockHook { clock, elapsed: 0 };
+    let outcome =
+        ota_begin_with_hooks(storage, binary, async |written| progress_fn(written), options, &mut hooks).await?;
+    Ok(OtaStats {
+        bytes_written: outcome.bytes_written,
+        write_duration_ticks: hooks.elapsed,
+    })
+}
+
+/// Starts an update with a buffer on the stack and the given hooks, shared by the variants of `ota_begin`
+async fn ota_begin_with_hooks<S: NorFlash, R: Read>(
+    storage: &mut S,
+    binary: R,
+    progress_fn: impl AsyncFnMut(usize),
+    options: OtaOptions<'_>,
+    hooks: &mut impl UpdateHooks<S>,
+) -> Result<OtaOutcome, OtaUpdateError<S, R::Error>> {
     // Check if there is already an update happening
-    if IS_UPDATING.swap(true, Ordering::SeqCst) {
+    let Some(_guard) = UpdateGuard::acquire() else {
         return Err(OtaUpdateError::AlreadyUpdating);
+    };
+
+    let mut buffer = [0; SECTOR_SIZE];
+    ota_begin_unguarded(storage, binary, progress_fn, options, &mut buffer, hooks).await
+}
+
+/// Extension points of the update for the variants of `ota_begin`, the defaults do nothing
+trait UpdateHooks<S: NorFlash> {
+    /// Future that completes when a single read of the `binary` takes too long, see `ota_begin_with_timeout`
+    fn read_timeout(&mut self) -> impl Future<Output = ()> {
+        core::future::pending()
+    }
+
+    /// Called with each buffer right before it is written at the absolute flash `offset`, see `ota_begin_with_transform`
+    fn transform(&mut self, _offset: u32, _buffer: &mut [u8]) {}
+
+    /// Called once the image is fully written and verified, returns false to reject it, see `ota_begin_signed`
+    fn verify_written_image(&mut self, _storage: &mut S, _partition: &PartitionEntry) -> bool {
+        true
+    }
+
+    /// Called with each erase and write of the app partition, see `ota_begin_with_clock`
+    fn timed<T>(&mut self, op: impl FnOnce() -> T) -> T {
+        op()
+    }
+
+    /// Write the boot entry that commits the update, see `ota_begin_multiwrite`
+    fn commit(
+        &mut self,
+        storage: &mut S,
+        layout: &OtaLayout,
+        data: EspOTAData,
+    ) -> Result<(), OtaInternalError<S>> {
+        write_ota_data(storage, layout, data)
+    }
+}
+
+struct NoHooks;
+
+impl<S: NorFlash> UpdateHooks<S> for NoHooks {}
+
+struct ReadTimeoutHook<T>(T);
+
+impl<S: NorFlash, T: FnMut() -> F, F: Future<Output = ()>> UpdateHooks<S> for ReadTimeoutHook<T> {
+    fn read_timeout(&mut self) -> impl Future<Output = ()> {
+        (self.0)()
+    }
+}
+
+struct TransformHook<T>(T);
+
+impl<S: NorFlash, T: FnMut(u32, &mut [u8])> UpdateHooks<S> for TransformHook<T> {
+    fn transform(&mut self, offset: u32, buffer: &mut [u8]) {
+        (self.0)(offset, buffer)
+    }
+}
+
+struct SignatureHook<T>(Option<T>);
+
+impl<S: NorFlash, T: FnOnce(&mut S, &PartitionEntry) -> Result<(), E>, E: Debug> UpdateHooks<S>
+    for SignatureHook<T>
+{
+    fn verify_written_image(&mut self, storage: &mut S, partition: &PartitionEntry) -> bool {
+        let Some(verify) = self.0.take() else {
+            return true;
+        };
+        verify(storage, partition)
+            .inspect_err(|e| warn!("Signature verification of the image failed: {e:?}"))
+            .is_ok()
+    }
+}
+
+struct MultiwriteHook;
+
+impl<S: MultiwriteNorFlash> UpdateHooks<S> for MultiwriteHook {
+    fn commit(
+        &mut self,
+        storage: &mut S,
+        layout: &OtaLayout,
+        data: EspOTAData,
+    ) -> Result<(), OtaInternalError<S>> {
+        ota_data::write_ota_data_multiwrite(storage, layout, data)
+    }
+}
+
+struct ClockHook<T> {
+    clock: T,
+    elapsed: u64,
+}
+
+impl<S: NorFlash, T: FnMut() -> u64> UpdateHooks<S> for ClockHook<T> {
+    fn timed<U>(&mut self, op: impl FnOnce() -> U) -> U {
+        let start = (self.clock)();
+        let result = op();
+        self.elapsed += (self.clock)().wrapping_sub(start);
+        result
     }
+}
+
+/// The update itself, the caller must ensure no other update of the same flash is in progress
+async fn ota_begin_unguarded<S: NorFlash, R: Read>(
+    storage: &mut S,
+    mut binary: R,
+    mut progress_fn: impl AsyncFnMut(usize),
+    options: OtaOptions<'_>,
+    data_buffer: &mut [u8],
+    hooks: &mut impl UpdateHooks<S>,
+) -> Result<OtaOutcome, OtaUpdateError<S, R::Error>> {
+    // Callers hold the update guard whenever the options override the retries, see `OtaUpdater::ota_begin_with_options`,
+    // so no other update can observe or restore the override
+    let _retries = RetriesOverride::new(options.flash_retries);
 
     // Check if we're in a valid state
-    let ota_data = read_ota_data(storage)?;
+    let layout = &options.layout;
+    let ota_data = read_ota_data(storage, layout)?;
     if !ota_data.is_valid() {
         return Err(OtaUpdateError::PendingVerify);
     }
 
     // Find partition to write to
     let booted_seq = ota_data.seq;
-    let new_seq = ota_data.seq + 1;
-    let new_part = ((new_seq - 1) % 2) as u8;
-    let ota_app =
-        find_partition_by_type(storage, PartitionType::App(AppPartitionType::Ota(new_part)))?;
-    log::info!("Starting OTA update. Current sequence is {booted_seq}, updating to sequence {new_seq} (partition {}).", ota_app.name());
+    let (new_seq, ota_app) = next_update_partition(storage, layout, booted_seq)?;
+    if ota_app.offset == ota_app_partition(storage, layout, booted_seq)?.offset {
+        return Err(OtaUpdateError::WouldEraseRunningPartition);
+    }
+    info!("Starting OTA update. Current sequence is {booted_seq}, updating to sequence {new_seq} (partition {}).", ota_app.name());
+
+    // Check the length upfront, so a problem is reported before the partition is erased
+    if let Some(len) = options.expected_len {
+        if len > ota_app.size {
+            return Err(OtaUpdateError::OutOfSpace {
+                partition_size: ota_app.size,
+                bytes_written: len,
+            });
+        }
+    }
+
+    // The part before the resume offset is kept, so it must be aligned to what can be erased separately
+    let resume_offset = options.resume_offset.unwrap_or(0);
+    if !resume_offset.is_multiple_of(S::ERASE_SIZE) || resume_offset > ota_app.size {
+        return Err(OtaUpdateError::InvalidResumeOffset {
+            offset: resume_offset,
+        });
+    }
+
+    // Find the lowest version we may install
+    let mut installed_version = match options.version_store {
+        Some(name) => store::read_value(storage, &layout.table(), name)?,
+        None => None,
+    };
+    if options.reject_downgrade {
+        let running_app = ota_app_partition(storage, layout, booted_seq)?;
+        match AppDescription::read(storage, running_app.offset) {
+            Ok(app) => installed_version = installed_version.max(Some(app.secure_version)),
+            Err(OtaInternalError::InvalidAppDescription) => {
+                warn!("The running app has no app description, not checking for a downgrade.")
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let mut incoming_version = None;
+
+    // Find the running image to compare with, the partition is only erased once the binary differs from it
+    let mut identical_to = None;
+    if options.skip_if_identical && resume_offset == 0 {
+        let running_app = ota_app_partition(storage, layout, booted_seq)?;
+        if let Some(image_len) = image::read_image_len(storage, &running_app)? {
+            identical_to = Some((running_app.offset, running_app.size, image_len));
+        }
+    }
 
     // Erase partition
-    storage
-        .erase(ota_app.offset, ota_app.offset + ota_app.size as u32)
-        .map_err(|e| OtaInternalError::NorFlashOpError(NorFlashOpError::StorageError(e)))?;
+    let enter_phase = |phase| {
+        if let Some(on_phase_change) = options.on_phase_change {
+            on_phase_change(phase);
+        }
+    };
+    if identical_to.is_none() {
+        enter_phase(OtaPhase::Erasing);
+        prepare_ota_app(storage, &options, &ota_app, new_seq, resume_offset, hooks).await?;
+    }
 
     // Write ota data to flash
+    enter_phase(OtaPhase::Writing);
+    #[cfg(feature = "sha256")]
+    let mut verifier = crate::verify::ImageHashVerifier::new();
+    #[cfg(feature = "sha256")]
+    let mut detached_sha = (options.expected_sha256.is_some() || options.report_sha256)
+        .then(sha2::Sha256::new);
+    let mut parser = ImageParser::new();
     let mut data_written = 0;
+    let mut first_byte = None;
     loop {
-        let mut data_buffer = [0; SECTOR_SIZE];
         let mut read_len = 0;
 
+        // When resuming, the part that was already written is read back from flash instead of from the `binary`,
+        // so it goes through the same checks and digests as the rest of the image
+        let replayed = data_written < resume_offset;
+        if replayed {
+            read_len = data_buffer.len().min(resume_offset - data_written);
+            let offset = ota_app.offset + data_written as u32;
+            retry_flash(|| storage.read(offset, &mut data_buffer[0..read_len]))
+                .map_err(|e| OtaInternalError::NorFlashOpError(NorFlashOpError::StorageError(e)))?;
+        }
+
         let mut is_done = false;
-        while read_len < SECTOR_SIZE {
-            let read = binary
-                .read(&mut data_buffer[read_len..])
+        while !replayed && read_len < data_buffer.len() {
+            let read = with_timeout(binary.read(&mut data_buffer[read_len..]), hooks.read_timeout())
                 .await
+                .ok_or(OtaUpdateError::ReadTimeout)?
                 .map_err(|e| OtaUpdateError::ReadError(e))?;
             if read == 0 {
                 is_done = true;
@@ -75,104 +704,1921 @@ pub async fn ota_begin<S: NorFlash, R: Read>(
         }
 
         if data_written + read_len > ota_app.size {
-            return Err(OtaUpdateError::OutOfSpace);
+            return Err(OtaUpdateError::OutOfSpace {
+                partition_size: ota_app.size,
+                bytes_written: data_written + read_len,
+            });
+        }
+
+        // The app description is in the first chunk, check it before writing anything
+        if data_written == 0
+            && (options.version_store.is_some()
+                || options.reject_downgrade
+                || options.expected_project_name.is_some())
+        {
+            let app = AppDescription::from_image(&data_buffer[0..read_len])
+                .ok_or(OtaUpdateError::InvalidAppDescription)?;
+            if let Some(expected) = options.expected_project_name {
+                if app.project_name() != expected {
+                    return Err(OtaUpdateError::ProjectNameMismatch);
+                }
+            }
+            if options.version_store.is_some() || options.reject_downgrade {
+                let incoming = app.secure_version;
+                if let Some(installed) = installed_version {
+                    if incoming < installed {
+                        return Err(OtaUpdateError::DowngradeBlocked {
+                            installed,
+                            incoming,
+                        });
+                    }
+                }
+                incoming_version = Some(incoming);
+            }
+        }
+        // The segment headers tell how large the image is, fail as soon as they show it doesn't fit,
+        // instead of after streaming the part that does fit
+        parser.feed(&data_buffer[0..read_len]);
+        if !parser.is_invalid() && parser.min_image_len() > ota_app.size {
+            return Err(OtaUpdateError::OutOfSpace {
+                partition_size: ota_app.size,
+                bytes_written: parser.min_image_len(),
+            });
+        }
+        #[cfg(feature = "sha256")]
+        verifier.feed(&data_buffer[0..read_len]);
+        #[cfg(feature = "sha256")]
+        if let Some(sha) = &mut detached_sha {
+            sha.update(&data_buffer[0..read_len]);
+        }
+        if data_written == 0 {
+            first_byte = data_buffer[0..read_len].first().copied();
+        }
+        if replayed {
+            data_written += read_len;
+            continue;
+        }
+
+        // Nothing needs to be written as long as the binary is identical to the running image
+        if let Some((running_offset, running_size, _)) = identical_to {
+            let offset = running_offset + data_written as u32;
+            if data_written + read_len <= running_size
+                && flash_matches(storage, offset, &data_buffer[0..read_len])?
+            {
+                data_written += read_len;
+                progress_fn(data_written).await;
+                if is_done {
+                    break;
+                }
+                continue;
+            }
+            info!("The binary differs from the running image after offset 0x{data_written:x}, installing it.");
+            enter_phase(OtaPhase::Erasing);
+            prepare_ota_app(storage, &options, &ota_app, new_seq, 0, hooks).await?;
+            hooks.timed(|| copy_flash(storage, running_offset, ota_app.offset, data_written))?;
+            identical_to = None;
+        }
+
+        if options.check_erased && read_len > 0 {
+            let offset = ota_app.offset + data_written as u32;
+            let mut word = [0; 4];
+            retry_flash(|| storage.read(offset, &mut word))
+                .map_err(|e| OtaInternalError::NorFlashOpError(NorFlashOpError::StorageError(e)))?;
+            if word != [options.erased_byte.unwrap_or(0xFF); 4] {
+                return Err(OtaUpdateError::SectorNotErased { offset });
+            }
         }
 
-        storage
-            .write(
-                ota_app.offset + data_written as u32,
-                &data_buffer[0..read_len],
-            )
+        // The last chunk may not be a multiple of the flash's `WRITE_SIZE`, pad it with erased bytes.
+        // The bootloader ignores anything after the end of the image.
+        let write_len = read_len.next_multiple_of(S::WRITE_SIZE);
+        data_buffer[read_len..write_len].fill(options.erased_byte.unwrap_or(0xFF));
+        let offset = ota_app.offset + data_written as u32;
+        hooks.transform(offset, &mut data_buffer[0..write_len]);
+        hooks.timed(|| retry_flash(|| storage.write(offset, &data_buffer[0..write_len])))
             .map_err(|e| OtaInternalError::NorFlashOpError(NorFlashOpError::StorageError(e)))?;
 
+        if options.verify_written || (options.verify_first_sector && data_written == 0) {
+            let mut readback = [0; 64];
+            for (i, expected) in data_buffer[0..read_len].chunks(readback.len()).enumerate() {
+                let offset = ota_app.offset + (data_written + i * readback.len()) as u32;
+                retry_flash(|| storage.read(offset, &mut readback[..expected.len()]))
+                    .map_err(|e| OtaInternalError::NorFlashOpError(NorFlashOpError::StorageError(e)))?;
+                if let Some(i) = readback.iter().zip(expected).position(|(a, b)| a != b) {
+                    return Err(OtaUpdateError::VerifyMismatch { offset: offset + i as u32 });
+                }
+            }
+        }
+
         data_written += read_len;
-        progress_fn(data_written);
+        progress_fn(data_written).await;
 
         if is_done {
             break;
         }
     }
 
+    if let Some((running_offset, _, running_len)) = identical_to {
+        if data_written >= running_len {
+            info!("The binary is identical to the running image, skipping the update.");
+            return Err(OtaUpdateError::ImageUnchanged);
+        }
+        // The binary is a truncated copy of the running image, write it so it fails the checks below
+        enter_phase(OtaPhase::Erasing);
+        prepare_ota_app(storage, &options, &ota_app, new_seq, 0, hooks).await?;
+        hooks.timed(|| copy_flash(storage, running_offset, ota_app.offset, data_written))?;
+    }
+
+    enter_phase(OtaPhase::Verifying);
+
+    // Check the binary had the announced length
+    if let Some(expected) = options.expected_len {
+        if data_written != expected {
+            return Err(OtaUpdateError::LengthMismatch {
+                expected,
+                actual: data_written,
+            });
+        }
+    }
+
+    // Verify the digest esptool appended to the image, if any
+    #[cfg(feature = "sha256")]
+    match verifier.finish() {
+        Some(true) => {}
+        None if !options.require_appended_hash => {}
+        _ => return Err(OtaUpdateError::HashMismatch),
+    }
+
+    // Verify the detached digest of the full binary, if any
+    #[cfg(feature = "sha256")]
+    let sha256: Option<[u8; 32]> = detached_sha.map(|sha| sha.finalize().into());
+    #[cfg(feature = "sha256")]
+    if let (Some(sha), Some(expected)) = (sha256, options.expected_sha256) {
+        if sha != expected {
+            return Err(OtaUpdateError::ChecksumMismatch);
+        }
+    }
+
+    // The bootloader refuses to boot anything that doesn't start with the image magic,
+    // failing here leaves the partition partially written but the boot entry unchanged
+    if first_byte != Some(ESP_IMAGE_MAGIC) {
+        return Err(OtaUpdateError::InvalidImageMagic);
+    }
+
+    // Let the caller verify the signature of the written image, see `ota_begin_signed`
+    if !hooks.verify_written_image(storage, &ota_app) {
+        return Err(OtaUpdateError::SignatureInvalid);
+    }
+
+    enter_phase(OtaPhase::Finalizing);
+
+    // Only updates that will be booted can cause a boot loop, so failed updates are not counted.
+    // This and the version store are written before the commit, so an error here leaves the old boot entry in place
+    // instead of failing an update that is already committed. If the commit fails after this, the counter is one too
+    // high and the version store may block installing a version between the running and the incoming one.
+    if let Some(failure_counter) = layout.failure_counter {
+        recovery::increment_failure_count_at(storage, &layout.table(), failure_counter)?;
+    }
+
+    // Raise the lowest version we may install in the future
+    if let (Some(name), Some(incoming)) = (options.version_store, incoming_version) {
+        if installed_version.is_none_or(|installed| incoming > installed) {
+            store::write_value(storage, &layout.table(), name, incoming)?;
+        }
+    }
+
     // Write new OTA data boot entry
-    let data = EspOTAData::new(new_seq, [0xFF; 20]);
-    write_ota_data(storage, data)?;
+    let data = EspOTAData::new(new_seq, options.label.unwrap_or([0xFF; 20]));
+    hooks.commit(storage, layout, data)?;
+
+    Ok(OtaOutcome {
+        bytes_written: data_written,
+        #[cfg(feature = "sha256")]
+        sha256: sha256.filter(|_| options.report_sha256),
+    })
+}
+
+/// Check whether `ota_begin` would accept the `binary`, without erasing or writing anything,
+/// e.g. to validate a download in a staging buffer before the partition is erased.
+/// Does the same checks on the state, the size, the image magic and (with the `sha256` feature) the appended digest,
+/// and returns the same errors. Does not mark an update as in progress.
+pub async fn ota_validate<S: NorFlash, R: Read>(
+    storage: &mut S,
+    binary: R,
+) -> Result<(), OtaUpdateError<S, R::Error>> {
+    ota_validate_with_layout(storage, &OtaLayout::default(), binary).await
+}
+
+/// Check whether `ota_begin` would accept the `binary`, like `ota_validate` but for a partition table with the given layout
+pub async fn ota_validate_with_layout<S: NorFlash, R: Read>(
+    storage: &mut S,
+    layout: &OtaLayout<'_>,
+    mut binary: R,
+) -> Result<(), OtaUpdateError<S, R::Error>> {
+    let ota_data = read_ota_data(storage, layout)?;
+    if !ota_data.is_valid() {
+        return Err(OtaUpdateError::PendingVerify);
+    }
+    let (_, ota_app) = next_update_partition(storage, layout, ota_data.seq)?;
+
+    #[cfg(feature = "sha256")]
+    let mut verifier = crate::verify::ImageHashVerifier::new();
+    let mut data_buffer = [0; SECTOR_SIZE];
+    let mut data_read = 0;
+    let mut first_byte = None;
+    loop {
+        let read = binary
+            .read(&mut data_buffer)
+            .await
+            .map_err(|e| OtaUpdateError::ReadError(e))?;
+        if read == 0 {
+            break;
+        }
+        if data_read + read > ota_app.size {
+            return Err(OtaUpdateError::OutOfSpace {
+                partition_size: ota_app.size,
+                bytes_written: data_read + read,
+            });
+        }
+        #[cfg(feature = "sha256")]
+        verifier.feed(&data_buffer[0..read]);
+        if data_read == 0 {
+            first_byte = Some(data_buffer[0]);
+        }
+        data_read += read;
+    }
 
+    #[cfg(feature = "sha256")]
+    if verifier.finish() == Some(false) {
+        return Err(OtaUpdateError::HashMismatch);
+    }
+    if first_byte != Some(ESP_IMAGE_MAGIC) {
+        return Err(OtaUpdateError::InvalidImageMagic);
+    }
     Ok(())
 }
 
+/// Clear the highest installed `secure_version` persisted in the `version_store` partition,
+/// so that images with any version may be installed again.
+/// Only intended for authorized recovery, this disables the downgrade protection until the next update.
+pub fn ota_clear_version_store<S: NorFlash>(
+    storage: &mut S,
+    version_store: &str,
+) -> Result<(), OtaInternalError<S>> {
+    ota_clear_version_store_with_layout(storage, &OtaLayout::default(), version_store)
+}
+
+/// Clear the `version_store`, like `ota_clear_version_store` but for a partition table with the given layout
+pub fn ota_clear_version_store_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+    version_store: &str,
+) -> Result<(), OtaInternalError<S>> {
+    store::clear_value(storage, &layout.table(), version_store)
+}
+
 /// Mark OTA update as valid.
 /// Must be called after an OTA update and reboot to confirm the new firmware works.
 /// May also be called after a reboot without OTA update.
 /// If the system reboots before an OTA update is accepted
 /// the update will be marked as aborted and will not be booted again.
+/// Returns `AlreadyUpdating` while an update is in progress, so a half-written image is never accepted.
 pub fn ota_accept<S: NorFlash>(storage: &mut S) -> Result<(), OtaInternalError<S>> {
-    let mut ota_data = read_ota_data(storage)?;
+    ota_accept_with_layout(storage, &OtaLayout::default())
+}
+
+/// Mark OTA update as valid, like `ota_accept` but for a partition table with the given layout.
+pub fn ota_accept_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+) -> Result<(), OtaInternalError<S>> {
+    if IS_UPDATING.load(Ordering::SeqCst) {
+        return Err(OtaInternalError::AlreadyUpdating);
+    }
+
+    ota_accept_unguarded(storage, layout)
+}
+
+/// Mark OTA update as valid like `ota_accept`, but only if the selected boot entry has sequence number `seq`,
+/// otherwise `SequenceMismatch` is returned and nothing is written.
+/// Makes scripted provisioning deterministic, since an entry that changed after `seq` was read is never accepted.
+pub fn ota_accept_seq<S: NorFlash>(storage: &mut S, seq: u32) -> Result<(), OtaInternalError<S>> {
+    ota_accept_seq_with_layout(storage, &OtaLayout::default(), seq)
+}
+
+/// Mark OTA update as valid if the selected boot entry has sequence number `seq`,
+/// like `ota_accept_seq` but for a partition table with the given layout
+pub fn ota_accept_seq_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+    seq: u32,
+) -> Result<(), OtaInternalError<S>> {
+    check_seq(storage, layout, seq)?;
+    ota_accept_with_layout(storage, layout)
+}
+
+/// Mark OTA update as valid, the caller must ensure no update of the same flash is in progress
+fn ota_accept_unguarded<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+) -> Result<(), OtaInternalError<S>> {
+    let mut ota_data = read_ota_data(storage, layout)?;
     match ota_data.state {
+        // The ota data was never written, so the factory app is running and there is nothing to accept
+        EspOTAState::Undefined if ota_data.seq == 0 => {}
         EspOTAState::PendingVerify => {
-            log::info!("Accepted pending OTA update");
+            info!("Accepted pending OTA update");
             ota_data.state = EspOTAState::Valid;
-            write_ota_data(storage, ota_data)?;
+            write_ota_data(storage, layout, ota_data)?;
+            reset_failure_count(storage, layout)?;
         },
         EspOTAState::New | EspOTAState::Undefined => {
-            log::warn!("Accepted OTA update from {:?} state", ota_data.state);
+            warn!("Accepted OTA update from {:?} state", ota_data.state);
             ota_data.state = EspOTAState::Valid;
-            write_ota_data(storage, ota_data)?;
+            write_ota_data(storage, layout, ota_data)?;
+            reset_failure_count(storage, layout)?;
         },
-        EspOTAState::Invalid | EspOTAState::Aborted => {
-            log::warn!("Detected rollback that was not processed by bootloader, rolling back manually.");
-            ota_data.state = EspOTAState::Valid;
-            ota_data.seq -= 1;
-            write_ota_data(storage, ota_data)?;
-        }
-        EspOTAState::Valid => {},
+        // Already accepted, e.g. after the bootloader rolled back a failed update, which must keep counting.
+        // `read_ota_data` never returns the entries the bootloader doesn't boot, so a rejected update is not seen here
+        EspOTAState::Valid | EspOTAState::Invalid | EspOTAState::Aborted => {},
     }
     Ok(())
 }
 
+/// Reset the failure counter of the layout, if it has one, once an update is accepted
+fn reset_failure_count<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+) -> Result<(), OtaInternalError<S>> {
+    match layout.failure_counter {
+        Some(failure_counter) => recovery::reset_failure_count_at(storage, &layout.table(), failure_counter),
+        None => Ok(()),
+    }
+}
+
 /// Explicitly mark an OTA update as invalid.
 /// May be called after an OTA update failed, but is not required.
 /// If the system reboots before an OTA update is confirmed as valid
 /// the update will be marked as aborted and will not be booted again.
 pub fn ota_reject<S: NorFlash>(storage: &mut S) -> Result<(), OtaInternalError<S>> {
-    let mut ota_data = read_ota_data(storage)?;
+    ota_reject_with_layout(storage, &OtaLayout::default())
+}
+
+/// Mark OTA update as invalid, like `ota_reject` but for a partition table with the given layout.
+pub fn ota_reject_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+) -> Result<(), OtaInternalError<S>> {
+    let mut ota_data = read_ota_data(storage, layout)?;
     match ota_data.state {
+        EspOTAState::Undefined if ota_data.seq == 0 => {
+            warn!("Tried to reject the factory app, ignoring request.");
+        }
         EspOTAState::PendingVerify => {
-            log::info!("Rejected pending OTA update");
+            info!("Rejected pending OTA update");
             ota_data.state = EspOTAState::Invalid;
-            write_ota_data(storage, ota_data)?;
+            write_ota_data(storage, layout, ota_data)?;
         }
         EspOTAState::New | EspOTAState::Undefined => {
-            log::warn!("Rejected OTA update from {:?} state", ota_data.state);
+            warn!("Rejected OTA update from {:?} state", ota_data.state);
             ota_data.state = EspOTAState::Invalid;
-            write_ota_data(storage, ota_data)?;
+            write_ota_data(storage, layout, ota_data)?;
         }
         EspOTAState::Valid => {
-            log::error!("Tried to reject OTA update that has already been accepted, ignoring request.");
+            error!("Tried to reject OTA update that has already been accepted, ignoring request.");
         }
         EspOTAState::Invalid => {
-            log::warn!("Tried to reject OTA update that has already been rejected, ignoring request.");
+            warn!("Tried to reject OTA update that has already been rejected, ignoring request.");
         }
         EspOTAState::Aborted => {
-            log::warn!("Tried to reject OTA update from aborted state, ignoring request.");
+            warn!("Tried to reject OTA update from aborted state, ignoring request.");
         }
     }
     Ok(())
 }
 
-// /// This function rolls back the app if the previous boot did not 
-// pub fn ota_rollback<S: NorFlash>(storage: &mut S) -> Result<(), OtaInternalError<S>> {
-//     
-// }
+/// Mark OTA update as invalid like `ota_reject`, but only if the selected boot entry has sequence number `seq`,
+/// otherwise `SequenceMismatch` is returned and nothing is written.
+pub fn ota_reject_seq<S: NorFlash>(storage: &mut S, seq: u32) -> Result<(), OtaInternalError<S>> {
+    ota_reject_seq_with_layout(storage, &OtaLayout::default(), seq)
+}
 
-/// Returns true if this OTA update has been accepted, i.e. with `ota_accept`
-pub fn ota_is_valid<S: NorFlash>(storage: &mut S) -> Result<bool, OtaInternalError<S>> {
-    Ok(read_ota_data(storage)?.is_valid())
+/// Mark OTA update as invalid if the selected boot entry has sequence number `seq`,
+/// like `ota_reject_seq` but for a partition table with the given layout
+pub fn ota_reject_seq_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+    seq: u32,
+) -> Result<(), OtaInternalError<S>> {
+    check_seq(storage, layout, seq)?;
+    ota_reject_with_layout(storage, layout)
 }
 
-/// Find the ota partition we're currently running on
-pub fn get_booted_partition<S: NorFlash>(storage: &mut S) -> Result<PartitionEntry, OtaInternalError<S>> {
-    let ota_data = read_ota_data(storage)?;
-    let booted_seq = ota_data.seq;
-    let new_part = ((booted_seq - 1) % 2) as u8;
-    find_partition_by_type(storage, PartitionType::App(AppPartitionType::Ota(new_part)))
-}
\ No newline at end of file
+/// Returns `SequenceMismatch` if the selected boot entry doesn't have sequence number `expected`
+fn check_seq<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+    expected: u32,
+) -> Result<(), OtaInternalError<S>> {
+    let actual = read_ota_data(storage, layout)?.seq;
+    if actual != expected {
+        return Err(OtaInternalError::SequenceMismatch { expected, actual });
+    }
+    Ok(())
+}
+
+/// Check both copies of the ota data and rewrite a corrupt copy from the other one.
+/// Reading the ota data never writes to flash, so call this to restore the redundancy,
+/// otherwise a single corrupt copy stays unnoticed until the other copy degrades as well.
+/// Returns true if a copy was repaired.
+pub fn ota_repair<S: NorFlash>(storage: &mut S) -> Result<bool, OtaInternalError<S>> {
+    ota_repair_with_layout(storage, &OtaLayout::default())
+}
+
+/// Repair the ota data, like `ota_repair` but for a partition table with the given layout.
+pub fn ota_repair_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+) -> Result<bool, OtaInternalError<S>> {
+    if IS_UPDATING.load(Ordering::SeqCst) {
+        return Err(OtaInternalError::AlreadyUpdating);
+    }
+
+    let repaired = ota_data::repair_ota_data(storage, layout)?;
+    if repaired {
+        warn!("Repaired a corrupt copy of the ota data");
+    }
+    Ok(repaired)
+}
+
+/// Make the bootloader boot the factory app on the next boot, by erasing both copies of the ota data.
+/// Returns `PartitionNotFound` if there is no factory partition, in which case nothing is changed.
+/// Returns `AlreadyUpdating` while an update is in progress.
+/// After the reset, the ota data reads as sequence number 0 in the `Undefined` state until the next update.
+/// This is the way to recover a device on which the images in all ota app partitions are bad.
+pub fn ota_reset_to_factory<S: NorFlash>(storage: &mut S) -> Result<(), OtaInternalError<S>> {
+    ota_reset_to_factory_with_layout(storage, &OtaLayout::default())
+}
+
+/// Make the bootloader boot the factory app, like `ota_reset_to_factory` but for a partition table with the given layout.
+pub fn ota_reset_to_factory_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+) -> Result<(), OtaInternalError<S>> {
+    if IS_UPDATING.load(Ordering::SeqCst) {
+        return Err(OtaInternalError::AlreadyUpdating);
+    }
+
+    info!("Resetting ota data to boot the factory app");
+    ota_data::reset_to_factory(storage, layout)
+}
+
+/// Erase both copies of the ota data, so the device boots like it was never updated, e.g. during development or provisioning.
+/// This is destructive: the record of which slot is active and whether it was accepted is lost,
+/// the bootloader boots the factory app on the next boot, or `ota_0` if there is no factory app.
+/// Unlike `ota_reset_to_factory` this doesn't require a factory partition.
+/// Returns the blank entry the ota data now reads as, with sequence number 0 in the `Undefined` state.
+/// Returns `AlreadyUpdating` while an update is in progress.
+pub fn erase_ota_data<S: NorFlash>(storage: &mut S) -> Result<EspOTAData, OtaInternalError<S>> {
+    erase_ota_data_with_layout(storage, &OtaLayout::default())
+}
+
+/// Erase both copies of the ota data, like `erase_ota_data` but for a partition table with the given layout
+pub fn erase_ota_data_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+) -> Result<EspOTAData, OtaInternalError<S>> {
+    if IS_UPDATING.load(Ordering::SeqCst) {
+        return Err(OtaInternalError::AlreadyUpdating);
+    }
+
+    warn!("Erasing the ota data");
+    ota_data::erase_ota_data(storage, layout)?;
+    read_ota_data(storage, layout)
+}
+
+/// Rewrite the ota data after the amount of ota app partitions changed, e.g. from 2 to 3 slots,
+/// so it selects the `running` partition again instead of whichever slot its sequence number maps to in the new table.
+/// - The `running` partition is the one returned by `get_booted_partition` before the partition table was changed,
+///   as afterwards the sequence number may map to another slot. It must also be an ota app partition in the new table.
+/// - The sequence number is increased to the next one that maps to that slot, keeping the state of the entry.
+/// - Returns true if the ota data was rewritten, false if it already selects the `running` partition.
+pub fn normalize_ota_data<S: NorFlash>(
+    storage: &mut S,
+    running: &PartitionEntry,
+) -> Result<bool, OtaInternalError<S>> {
+    normalize_ota_data_with_layout(storage, &OtaLayout::default(), running)
+}
+
+/// Rewrite the ota data after the amount of ota app partitions changed, for a partition table with the given layout
+pub fn normalize_ota_data_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+    running: &PartitionEntry,
+) -> Result<bool, OtaInternalError<S>> {
+    if IS_UPDATING.load(Ordering::SeqCst) {
+        return Err(OtaInternalError::AlreadyUpdating);
+    }
+
+    let mut ota_data = read_ota_data(storage, layout)?;
+    if ota_app_partition(storage, layout, ota_data.seq)?.offset == running.offset {
+        return Ok(false);
+    }
+
+    let ota_count = layout.count_ota_partitions(storage)?;
+    let mut slot = None;
+    for i in 0..ota_count as u8 {
+        let partition = layout.find_partition_by_type(storage, PartitionType::App(AppPartitionType::Ota(i)))?;
+        if partition.offset == running.offset {
+            slot = Some(i);
+        }
+    }
+    let Some(slot) = slot else {
+        return Err(OtaInternalError::PartitionNotFound);
+    };
+
+    let mut new_seq = next_seq(ota_data.seq)?;
+    while ota_slot(new_seq, ota_count) != slot {
+        new_seq = next_seq(new_seq)?;
+    }
+    info!("Renumbering ota data from sequence {} to {new_seq}, so partition {} keeps booting.", ota_data.seq, running.name());
+    ota_data.seq = new_seq;
+    write_ota_data(storage, layout, ota_data)?;
+    Ok(true)
+}
+
+/// Roll back to the previously installed app, e.g. because the running app detected a problem.
+/// The previous app is marked as valid, the caller should reboot to activate it.
+/// Returns `NothingToRollBackTo` if no app was installed before the running one,
+/// or the partition it was installed to no longer contains an image.
+pub fn ota_rollback<S: NorFlash>(storage: &mut S) -> Result<(), OtaInternalError<S>> {
+    ota_rollback_with_layout(storage, &OtaLayout::default())
+}
+
+/// Roll back to the previously installed app, like `ota_rollback` but for a partition table with the given layout.
+pub fn ota_rollback_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+) -> Result<(), OtaInternalError<S>> {
+    if IS_UPDATING.load(Ordering::SeqCst) {
+        return Err(OtaInternalError::AlreadyUpdating);
+    }
+
+    let mut ota_data = read_ota_data(storage, layout)?;
+    if ota_data.seq <= 1 {
+        return Err(OtaInternalError::NothingToRollBackTo);
+    }
+    let previous_seq = ota_data.seq - 1;
+    let previous_app = ota_app_partition(storage, layout, previous_seq)?;
+    if !image::partition_has_image(storage, &previous_app)? {
+        return Err(OtaInternalError::NothingToRollBackTo);
+    }
+
+    info!("Rolling back to sequence {previous_seq} (partition {}).", previous_app.name());
+    let mut previous = EspOTAData::new(previous_seq, [0xFF; 20]);
+    previous.state = EspOTAState::Valid;
+    write_ota_data(storage, layout, previous)?;
+
+    // The running entry still has the higher sequence number, so it must no longer be selected
+    ota_data.state = EspOTAState::Invalid;
+    write_ota_data(storage, layout, ota_data)
+}
+
+/// Returns true if this OTA update has been accepted, i.e. with `ota_accept`
+pub fn ota_is_valid<S: NorFlash>(storage: &mut S) -> Result<bool, OtaInternalError<S>> {
+    ota_is_valid_with_layout(storage, &OtaLayout::default())
+}
+
+/// Returns true if this OTA update has been accepted, for a partition table with the given layout
+pub fn ota_is_valid_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+) -> Result<bool, OtaInternalError<S>> {
+    Ok(read_ota_data(storage, layout)?.is_valid())
+}
+
+/// Returns true if this is the first boot of an update that hasn't been accepted yet,
+/// i.e. the app should run its self-tests and call `ota_accept`, instead of on every boot.
+pub fn is_pending_verify<S: NorFlash>(storage: &mut S) -> Result<bool, OtaInternalError<S>> {
+    is_pending_verify_with_layout(storage, &OtaLayout::default())
+}
+
+/// Returns true if this is the first boot of an update that hasn't been accepted yet,
+/// for a partition table with the given layout
+pub fn is_pending_verify_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+) -> Result<bool, OtaInternalError<S>> {
+    Ok(read_ota_data(storage, layout)?.state == EspOTAState::PendingVerify)
+}
+
+/// Read the state of the selected boot entry, e.g. to show it on a diagnostics screen
+pub fn get_ota_state<S: NorFlash>(storage: &mut S) -> Result<EspOTAState, OtaInternalError<S>> {
+    get_ota_state_with_layout(storage, &OtaLayout::default())
+}
+
+/// Read the state of the selected boot entry, for a partition table with the given layout
+pub fn get_ota_state_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+) -> Result<EspOTAState, OtaInternalError<S>> {
+    Ok(read_ota_data(storage, layout)?.state)
+}
+
+/// Read the sequence number of the selected boot entry, which increases by one with every update
+pub fn get_ota_seq<S: NorFlash>(storage: &mut S) -> Result<u32, OtaInternalError<S>> {
+    get_ota_seq_with_layout(storage, &OtaLayout::default())
+}
+
+/// Read the sequence number of the selected boot entry, for a partition table with the given layout
+pub fn get_ota_seq_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+) -> Result<u32, OtaInternalError<S>> {
+    Ok(read_ota_data(storage, layout)?.seq)
+}
+
+/// Find the ota partition we're currently running on
+pub fn get_booted_partition<S: NorFlash>(storage: &mut S) -> Result<PartitionEntry, OtaInternalError<S>> {
+    get_booted_partition_with_layout(storage, &OtaLayout::default())
+}
+
+/// Find the ota partition we're currently running on, for a partition table with the given layout
+pub fn get_booted_partition_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+) -> Result<PartitionEntry, OtaInternalError<S>> {
+    let ota_data = read_ota_data(storage, layout)?;
+    ota_app_partition(storage, layout, ota_data.seq)
+}
+
+/// The index of the ota slot we're currently running on, i.e. `n` of partition `ota_n`,
+/// which saves parsing the name of `get_booted_partition` to e.g. compare it with the server's record.
+/// Returns `None` while the factory app is running, i.e. before the first update.
+pub fn get_running_ota_slot<S: NorFlash>(storage: &mut S) -> Result<Option<u8>, OtaInternalError<S>> {
+    get_running_ota_slot_with_layout(storage, &OtaLayout::default())
+}
+
+/// The index of the ota slot we're currently running on, for a partition table with the given layout
+pub fn get_running_ota_slot_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+) -> Result<Option<u8>, OtaInternalError<S>> {
+    let ota_data = read_ota_data(storage, layout)?;
+    let ota_count = layout.count_ota_partitions(storage)?;
+    let has_factory = ota_data.seq != 0 || has_factory_partition(storage, layout)?;
+    Ok(booted_slot(ota_data.seq, ota_count, has_factory))
+}
+
+/// Snapshot of the OTA state of the device, see `ota_status`
+#[derive(Debug, Clone)]
+pub struct OtaStatus {
+    /// Index of the ota slot that is running, `None` while the factory app is running, see `get_running_ota_slot`
+    pub running_slot: Option<u8>,
+    /// The app partition that is running
+    pub running_partition: PartitionEntry,
+    /// State of the selected boot entry
+    pub state: EspOTAState,
+    /// Sequence number of the selected boot entry
+    pub seq: u32,
+    /// Returns true if the running app is an update that hasn't been accepted yet, see `is_pending_verify`
+    pub pending_verify: bool,
+}
+
+/// Entries of the partition table that `ota_status` parses upfront,
+/// the partition table is read for every lookup instead if it has more entries
+const STATUS_PARTITIONS: usize = 24;
+
+/// Read the OTA state of the device in one call, e.g. for a device management dashboard.
+/// The ota data and the partition table are each read once, instead of once per getter.
+pub fn ota_status<S: NorFlash>(storage: &mut S) -> Result<OtaStatus, OtaInternalError<S>> {
+    ota_status_with_layout(storage, &OtaLayout::default())
+}
+
+/// Read the OTA state of the device in one call, for a partition table with the given layout
+pub fn ota_status_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+) -> Result<OtaStatus, OtaInternalError<S>> {
+    if layout.partitions.is_some() {
+        return read_ota_status(storage, layout);
+    }
+    match ParsedPartitions::<STATUS_PARTITIONS>::parse_at(storage, &layout.table()) {
+        Ok(parsed) => {
+            let layout = OtaLayout {
+                partitions: Some(parsed.entries()),
+                ..*layout
+            };
+            read_ota_status(storage, &layout)
+        }
+        Err(OtaInternalError::TooManyPartitions { .. }) => read_ota_status(storage, layout),
+        Err(e) => Err(e),
+    }
+}
+
+fn read_ota_status<S: NorFlash>(storage: &mut S, layout: &OtaLayout) -> Result<OtaStatus, OtaInternalError<S>> {
+    let ota_data = read_ota_data(storage, layout)?;
+    let running_partition = ota_app_partition(storage, layout, ota_data.seq)?;
+    let ota_count = layout.count_ota_partitions(storage)?;
+    let has_factory = ota_data.seq != 0 || has_factory_partition(storage, layout)?;
+    let running_slot = booted_slot(ota_data.seq, ota_count, has_factory);
+
+    Ok(OtaStatus {
+        running_slot,
+        running_partition,
+        state: ota_data.state,
+        seq: ota_data.seq,
+        pending_verify: ota_data.state == EspOTAState::PendingVerify,
+    })
+}
+
+/// Find the ota partition the next update would be written to, without starting an update
+pub fn get_next_update_partition<S: NorFlash>(
+    storage: &mut S,
+) -> Result<PartitionEntry, OtaInternalError<S>> {
+    get_next_update_partition_with_layout(storage, &OtaLayout::default())
+}
+
+/// Find the ota partition the next update would be written to, for a partition table with the given layout
+pub fn get_next_update_partition_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+) -> Result<PartitionEntry, OtaInternalError<S>> {
+    let ota_data = read_ota_data(storage, layout)?;
+    Ok(next_update_partition(storage, layout, ota_data.seq)?.1)
+}
+
+/// The size in bytes of the partition the next update would be written to, i.e. the largest image it can install
+pub fn next_partition_capacity<S: NorFlash>(storage: &mut S) -> Result<usize, OtaInternalError<S>> {
+    next_partition_capacity_with_layout(storage, &OtaLayout::default())
+}
+
+/// The size in bytes of the partition the next update would be written to,
+/// like `next_partition_capacity` but for a partition table with the given layout
+pub fn next_partition_capacity_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+) -> Result<usize, OtaInternalError<S>> {
+    Ok(get_next_update_partition_with_layout(storage, layout)?.size)
+}
+
+/// Returns true if an image of `len` bytes fits the partition the next update would be written to,
+/// e.g. to check an advertised image size before downloading the image
+pub fn fits_in_next_partition<S: NorFlash>(
+    storage: &mut S,
+    len: usize,
+) -> Result<bool, OtaInternalError<S>> {
+    fits_in_next_partition_with_layout(storage, &OtaLayout::default(), len)
+}
+
+/// Returns true if an image of `len` bytes fits the partition the next update would be written to,
+/// like `fits_in_next_partition` but for a partition table with the given layout
+pub fn fits_in_next_partition_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+    len: usize,
+) -> Result<bool, OtaInternalError<S>> {
+    Ok(len <= next_partition_capacity_with_layout(storage, layout)?)
+}
+
+/// Find the app partition that is booted for the given sequence number.
+/// Sequence number 0, i.e. if the ota data was never written, boots the factory app partition,
+/// or `ota_0` if the partition table has no factory app.
+fn ota_app_partition<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+    seq: u32,
+) -> Result<PartitionEntry, OtaInternalError<S>> {
+    if seq == 0 {
+        return match layout.find_partition_by_type(storage, PartitionType::App(AppPartitionType::Factory)) {
+            Err(OtaInternalError::PartitionNotFound) => {
+                layout.find_partition_by_type(storage, PartitionType::App(AppPartitionType::Ota(0)))
+            }
+            result => result,
+        };
+    }
+    let slot = ota_slot(seq, layout.count_ota_partitions(storage)?);
+    layout.find_partition_by_type(storage, PartitionType::App(AppPartitionType::Ota(slot)))
+}
+
+/// Returns true if the partition table has a factory app partition
+fn has_factory_partition<S: NorFlash>(storage: &mut S, layout: &OtaLayout) -> Result<bool, OtaInternalError<S>> {
+    match layout.find_partition_by_type(storage, PartitionType::App(AppPartitionType::Factory)) {
+        Ok(_) => Ok(true),
+        Err(OtaInternalError::PartitionNotFound) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Find the sequence number and the app partition of the update after the entry with sequence number `seq`,
+/// see `next_update_seq`. The caller still has to check that this is not the running app partition.
+fn next_update_partition<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+    seq: u32,
+) -> Result<(u32, PartitionEntry), OtaInternalError<S>> {
+    let ota_count = layout.count_ota_partitions(storage)?;
+    // Whether there is a factory app only matters while the ota data is blank
+    let has_factory = seq != 0 || has_factory_partition(storage, layout)?;
+    let new_seq = next_update_seq(seq, ota_count, has_factory).ok_or(OtaInternalError::SequenceExhausted)?;
+    let slot = ota_slot(new_seq, ota_count);
+    let partition = layout.find_partition_by_type(storage, PartitionType::App(AppPartitionType::Ota(slot)))?;
+    Ok((new_seq, partition))
+}
+
+/// Read the app description embedded in the bootloader image at `bootloader_offset`,
+/// which is 0x1000 on the esp32 and esp32-s2 and 0x0 on the newer chips.
+/// Returns `None` if the bootloader does not contain an app description,
+/// note that bootloaders of esp-idf 5.2 and newer embed an `esp_bootloader_desc_t` instead, which is not parsed.
+pub fn bootloader_app_description<S: NorFlash>(
+    storage: &mut S,
+    bootloader_offset: u32,
+) -> Result<Option<AppDescription>, OtaInternalError<S>> {
+    match AppDescription::read(storage, bootloader_offset) {
+        Ok(app) => Ok(Some(app)),
+        Err(OtaInternalError::InvalidAppDescription) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns true if the running image is in the partition that otadata selects.
+/// `running_offset` is the flash offset of the running image (or any offset within it), e.g. from a linker symbol.
+/// A false result means the bootloader booted something otadata didn't select, for example after a silent rollback.
+pub fn running_matches_otadata<S: NorFlash>(
+    storage: &mut S,
+    running_offset: u32,
+) -> Result<bool, OtaInternalError<S>> {
+    running_matches_otadata_with_layout(storage, &OtaLayout::default(), running_offset)
+}
+
+/// Returns true if the running image is in the partition that otadata selects,
+/// like `running_matches_otadata` but for a partition table with the given layout
+pub fn running_matches_otadata_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+    running_offset: u32,
+) -> Result<bool, OtaInternalError<S>> {
+    let partition = get_booted_partition_with_layout(storage, layout)?;
+    Ok((partition.offset..partition.offset + partition.size as u32).contains(&running_offset))
+}
+
+/// Read the app description of the image in a partition
+pub fn read_app_description<S: NorFlash>(
+    storage: &mut S,
+    partition: &PartitionEntry,
+) -> Result<AppDescription, OtaInternalError<S>> {
+    AppDescription::read(storage, partition.offset)
+}
+
+/// Read the app description of the running image, i.e. its version and build information
+pub fn running_app_description<S: NorFlash>(
+    storage: &mut S,
+) -> Result<AppDescription, OtaInternalError<S>> {
+    running_app_description_with_layout(storage, &OtaLayout::default())
+}
+
+/// Read the app description of the running image,
+/// like `running_app_description` but for a partition table with the given layout
+pub fn running_app_description_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+) -> Result<AppDescription, OtaInternalError<S>> {
+    let partition = get_booted_partition_with_layout(storage, layout)?;
+    AppDescription::read(storage, partition.offset)
+}
+
+/// Returns true if ota app partition `ota_<slot>` contains a plausibly bootable image, without changing anything,
+/// e.g. to check a slot before relying on it, as an interrupted update leaves a half-written image behind.
+/// The image header and the segment headers must be valid and fit the partition.
+/// With the `sha256` feature, an appended SHA-256 digest is also verified, which reads the full image.
+pub fn is_slot_bootable<S: NorFlash>(storage: &mut S, slot: u8) -> Result<bool, OtaInternalError<S>> {
+    is_slot_bootable_with_layout(storage, &OtaLayout::default(), slot)
+}
+
+/// Returns true if ota app partition `ota_<slot>` contains a plausibly bootable image,
+/// like `is_slot_bootable` but for a partition table with the given layout
+pub fn is_slot_bootable_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+    slot: u8,
+) -> Result<bool, OtaInternalError<S>> {
+    let partition = layout.find_partition_by_type(storage, PartitionType::App(AppPartitionType::Ota(slot)))?;
+    let Some(image_len) = image::read_image_len(storage, &partition)? else {
+        return Ok(false);
+    };
+
+    #[cfg(feature = "sha256")]
+    {
+        let mut verifier = crate::verify::ImageHashVerifier::new();
+        let mut buffer = [0; SECTOR_SIZE];
+        let mut offset = 0;
+        while !verifier.is_complete() && offset < image_len {
+            let len = SECTOR_SIZE.min(image_len - offset);
+            retry_flash(|| storage.read(partition.offset + offset as u32, &mut buffer[..len]))
+                .map_err(|e| NorFlashOpError::StorageError(e))?;
+            verifier.feed(&buffer[..len]);
+            offset += len;
+        }
+        if verifier.finish() == Some(false) {
+            return Ok(false);
+        }
+    }
+    #[cfg(not(feature = "sha256"))]
+    let _ = image_len;
+
+    Ok(true)
+}
+
+/// Report on the contents of one ota app partition, see `audit_slots`
+#[derive(Debug, Clone)]
+pub struct SlotReport {
+    /// The ota app partition
+    pub partition: PartitionEntry,
+    /// Size of the image in the partition, including the appended digest.
+    /// `None` if the partition doesn't contain a valid image header.
+    pub image_len: Option<usize>,
+    /// App description of the image, `None` if there is no valid app description
+    pub app: Option<AppDescription>,
+    /// Returns true if otadata selects this partition, false for all partitions if otadata is corrupt
+    pub live: bool,
+}
+
+impl SlotReport {
+    /// Returns true if the partition contains a valid image header
+    pub fn has_image(&self) -> bool {
+        self.image_len.is_some()
+    }
+}
+
+/// Report on the contents of all ota app partitions
+#[derive(Debug, Clone)]
+pub struct SlotAudit {
+    /// Report per ota app partition, indexed by ota number. `None` if there is no such partition.
+    pub slots: [Option<SlotReport>; MAX_OTA_PARTITIONS],
+}
+
+/// Inspect the images in all ota app partitions, both active and inactive,
+/// for example to detect a corrupt standby image before a rollback is needed
+/// or to show the version in each slot on a dashboard
+pub fn audit_slots<S: NorFlash>(storage: &mut S) -> Result<SlotAudit, OtaInternalError<S>> {
+    audit_slots_with_layout(storage, &OtaLayout::default())
+}
+
+/// Inspect the images in all ota app partitions, like `audit_slots` but for a partition table with the given layout
+pub fn audit_slots_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+) -> Result<SlotAudit, OtaInternalError<S>> {
+    let mut audit = SlotAudit {
+        slots: [const { None }; MAX_OTA_PARTITIONS],
+    };
+    let partitions = layout.find_ota_partitions(storage)?;
+    let ota_count = partitions.iter().flatten().count();
+    let live = match read_ota_data(storage, layout) {
+        Ok(ota_data) => {
+            let has_factory = ota_data.seq != 0 || has_factory_partition(storage, layout)?;
+            booted_slot(ota_data.seq, ota_count, has_factory)
+        }
+        Err(OtaInternalError::OtaDataCorrupt) => None,
+        Err(e) => return Err(e),
+    };
+    for ((index, report), partition) in audit.slots.iter_mut().enumerate().zip(partitions) {
+        let Some(partition) = partition else {
+            continue;
+        };
+        let image_len = image::read_image_len(storage, &partition)?;
+        let app = match AppDescription::read(storage, partition.offset) {
+            Ok(app) => Some(app),
+            Err(OtaInternalError::InvalidAppDescription) => None,
+            Err(e) => return Err(e),
+        };
+        *report = Some(SlotReport {
+            partition,
+            image_len,
+            app,
+            live: live == Some(index as u8),
+        });
+    }
+    Ok(audit)
+}
+
+/// Yield to the executor once, so other tasks can run
+async fn yield_now() {
+    let mut yielded = false;
+    core::future::poll_fn(|cx| {
+        if yielded {
+            Poll::Ready(())
+        } else {
+            yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+/// Erase the partition from offset `from` on, and write a non-bootable entry for the new sequence,
+/// so an interrupted update keeps booting the old firmware
+async fn prepare_ota_app<S: NorFlash>(
+    storage: &mut S,
+    options: &OtaOptions<'_>,
+    ota_app: &PartitionEntry,
+    new_seq: u32,
+    from: usize,
+    hooks: &mut impl UpdateHooks<S>,
+) -> Result<(), OtaInternalError<S>> {
+    let erase_chunk = match options.max_erase_chunk_sectors {
+        Some(sectors) => sectors.max(1) * S::ERASE_SIZE,
+        None => ota_app.size,
+    };
+    let mut erased = from;
+    while erased < ota_app.size {
+        let len = erase_chunk.min(ota_app.size - erased);
+        let from = ota_app.offset + erased as u32;
+        hooks.timed(|| retry_flash(|| storage.erase(from, from + len as u32)))
+            .map_err(|e| NorFlashOpError::StorageError(e))?;
+        erased += len;
+        if erased < ota_app.size {
+            yield_now().await;
+        }
+    }
+
+    write_pending_ota_data(storage, &options.layout, new_seq)
+}
+
+/// Returns true if the flash at `offset` contains `data`
+fn flash_matches<S: NorFlash>(
+    storage: &mut S,
+    offset: u32,
+    data: &[u8],
+) -> Result<bool, OtaInternalError<S>> {
+    let mut buffer = [0; 64];
+    for (i, expected) in data.chunks(buffer.len()).enumerate() {
+        let offset = offset + (i * buffer.len()) as u32;
+        retry_flash(|| storage.read(offset, &mut buffer[..expected.len()]))
+            .map_err(|e| NorFlashOpError::StorageError(e))?;
+        if buffer[..expected.len()] != *expected {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Copy `len` bytes of flash from offset `from` to the erased flash at offset `to`,
+/// rounded up to a multiple of the flash's `WRITE_SIZE`
+fn copy_flash<S: NorFlash>(
+    storage: &mut S,
+    from: u32,
+    to: u32,
+    len: usize,
+) -> Result<(), OtaInternalError<S>> {
+    let mut buffer = [0; 256];
+    let mut copied = 0;
+    while copied < len {
+        let chunk = (len - copied).next_multiple_of(S::WRITE_SIZE).min(buffer.len());
+        let (from, to) = (from + copied as u32, to + copied as u32);
+        retry_flash(|| storage.read(from, &mut buffer[..chunk]))
+            .map_err(|e| NorFlashOpError::StorageError(e))?;
+        retry_flash(|| storage.write(to, &buffer[..chunk]))
+            .map_err(|e| NorFlashOpError::StorageError(e))?;
+        copied += chunk;
+    }
+    Ok(())
+}
+
+/// Await `future`, returning `None` if `timeout` completes first
+async fn with_timeout<T>(future: impl Future<Output = T>, timeout: impl Future<Output = ()>) -> Option<T> {
+    let mut future = core::pin::pin!(future);
+    let mut timeout = core::pin::pin!(timeout);
+    core::future::poll_fn(|cx| {
+        if let Poll::Ready(value) = future.as_mut().poll(cx) {
+            Poll::Ready(Some(value))
+        } else if timeout.as_mut().poll(cx).is_ready() {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+/// The index of the ota app partition that is booted for the given sequence number,
+/// when the partition table contains `ota_count` ota app partitions
+fn ota_slot(seq: u32, ota_count: usize) -> u8 {
+    ((seq - 1) % ota_count.max(1) as u32) as u8
+}
+
+/// The sequence number of the entry after `seq`.
+/// The bootloader never selects an entry with sequence number `u32::MAX`, so instead of wrapping around,
+/// which would make every older entry be selected over the new one, `SequenceExhausted` is returned.
+/// At one update per minute, this is reached after more than 8000 years.
+fn next_seq<S: NorFlash>(seq: u32) -> Result<u32, OtaInternalError<S>> {
+    seq.checked_add(1)
+        .filter(|&next| next != u32::MAX)
+        .ok_or(OtaInternalError::SequenceExhausted)
+}
+
+/// The sequence number of the update after the entry with sequence number `seq`,
+/// `None` if the sequence numbers are exhausted, see `next_seq`.
+/// Blank ota data boots `ota_0` if the partition table has no factory app,
+/// so the first update then skips to sequence number 2, i.e. `ota_1`, instead of overwriting the running app.
+/// `has_factory` is ignored for any other sequence number.
+fn next_update_seq(seq: u32, ota_count: usize, has_factory: bool) -> Option<u32> {
+    let next = if seq == 0 && !has_factory && ota_count > 1 {
+        2
+    } else {
+        seq.checked_add(1)?
+    };
+    Some(next).filter(|&next| next != u32::MAX)
+}
+
+/// The index of the ota app partition that is booted for the given sequence number,
+/// `None` if that is the factory app, see `ota_app_partition`
+fn booted_slot(seq: u32, ota_count: usize, has_factory: bool) -> Option<u8> {
+    match seq {
+        0 if has_factory => None,
+        0 => Some(0),
+        seq => Some(ota_slot(seq, ota_count)),
+    }
+}
+
+/// What `ota_check_consistency` does when otadata points at a slot that doesn't contain an image
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum InconsistencyPolicy {
+    /// Return `OtaDataInconsistent`
+    Error,
+    /// Point otadata at the other slot if that does contain an image
+    UseOtherSlot,
+}
+
+/// Check that the slot otadata points at actually contains an image,
+/// which may not be the case if otadata was reset by a manual flash.
+/// If it doesn't, `policy` decides how to recover.
+/// Returns the partition otadata points at after the check.
+pub fn ota_check_consistency<S: NorFlash>(
+    storage: &mut S,
+    policy: InconsistencyPolicy,
+) -> Result<PartitionEntry, OtaInternalError<S>> {
+    ota_check_consistency_with_layout(storage, &OtaLayout::default(), policy)
+}
+
+/// Check that the slot otadata points at contains an image,
+/// like `ota_check_consistency` but for a partition table with the given layout
+pub fn ota_check_consistency_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+    policy: InconsistencyPolicy,
+) -> Result<PartitionEntry, OtaInternalError<S>> {
+    let mut ota_data = read_ota_data(storage, layout)?;
+    let live_app = ota_app_partition(storage, layout, ota_data.seq)?;
+    if image::partition_has_image(storage, &live_app)? {
+        return Ok(live_app);
+    }
+    if policy == InconsistencyPolicy::Error {
+        return Err(OtaInternalError::OtaDataInconsistent);
+    }
+
+    let (other_seq, other_app) = next_update_partition(storage, layout, ota_data.seq)?;
+    if !image::partition_has_image(storage, &other_app)? {
+        return Err(OtaInternalError::OtaDataInconsistent);
+    }
+
+    warn!(
+        "Otadata points at partition {} which contains no image, falling back to partition {}.",
+        live_app.name(),
+        other_app.name()
+    );
+    ota_data = EspOTAData::new(other_seq, [0xFF; 20]);
+    ota_data.state = EspOTAState::Valid;
+    write_ota_data(storage, layout, ota_data)?;
+    Ok(other_app)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn update_is_booted_from_the_slot_it_was_written_to() {
+        let _serial = serial();
+        for slots in [2, 3] {
+            let mut flash = MockFlash::new(slots);
+            for seq in 1..=3 * slots as u32 + 1 {
+                let target = get_next_update_partition(&mut flash).unwrap();
+                let image = image(100 + seq as usize);
+                update(&mut flash, &image).unwrap();
+
+                assert_eq!(&flash.data[target.offset as usize..][..image.len()], &image[..]);
+                assert_eq!(get_ota_seq(&mut flash).unwrap(), seq);
+                assert_eq!(get_booted_partition(&mut flash).unwrap().offset, target.offset);
+                let slot = get_running_ota_slot(&mut flash).unwrap();
+                assert_eq!(slot, Some(((seq - 1) % slots as u32) as u8));
+                assert_eq!(target.offset, test_utils::slot_offset(slot.unwrap()));
+                ota_accept(&mut flash).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn booted_and_next_slot_follow_the_sequence_number() {
+        for slots in [2, 3] {
+            let mut flash = MockFlash::new(slots);
+            for seq in 1..=3 * slots as u32 + 1 {
+                flash.set_ota_data_both(seq, EspOTAState::Valid);
+                let booted = ((seq - 1) % slots as u32) as u8;
+                let next = (seq % slots as u32) as u8;
+                assert_eq!(get_running_ota_slot(&mut flash).unwrap(), Some(booted));
+                assert_eq!(get_booted_partition(&mut flash).unwrap().offset, slot_offset(booted));
+                assert_eq!(get_next_update_partition(&mut flash).unwrap().offset, slot_offset(next));
+            }
+        }
+    }
+
+    #[test]
+    fn power_loss_before_the_commit_keeps_booting_the_old_firmware() {
+        let _serial = serial();
+        let image = image(3 * SECTOR_SIZE);
+        let fresh_flash = || {
+            let mut flash = MockFlash::new(2);
+            flash.set_ota_data_both(1, EspOTAState::Valid);
+            flash
+        };
+
+        let mut flash = fresh_flash();
+        update(&mut flash, &image).unwrap();
+        assert_eq!(get_ota_seq(&mut flash).unwrap(), 2);
+        let operations = flash.erases + flash.writes;
+
+        // Lose power before each erase or write of the update, the last one being the write of the new boot entry
+        for ops in 0..operations {
+            let mut flash = fresh_flash();
+            flash.ops_until_power_loss = Some(ops);
+            assert!(update(&mut flash, &image).is_err(), "power loss after {ops} operations");
+            flash.ops_until_power_loss = None;
+
+            assert_eq!(get_ota_seq(&mut flash).unwrap(), 1, "power loss after {ops} operations");
+            assert_eq!(get_ota_state(&mut flash).unwrap(), EspOTAState::Valid);
+            assert_eq!(get_booted_partition(&mut flash).unwrap().offset, slot_offset(0));
+        }
+    }
+
+    #[test]
+    fn failed_update_releases_the_update_flag() {
+        let _serial = serial();
+        let mut flash = MockFlash::new(2);
+        let result = block_on(ota_begin(&mut flash, FailingReader, |_| {}));
+        assert!(matches!(result, Err(OtaUpdateError::ReadError(_))));
+        assert!(!is_update_in_progress());
+
+        update(&mut flash, &image(100)).unwrap();
+        assert_eq!(get_ota_seq(&mut flash).unwrap(), 1);
+    }
+
+    #[test]
+    fn binary_that_is_not_an_esp_image_leaves_the_boot_entry_untouched() {
+        let _serial = serial();
+        let mut flash = MockFlash::new(2);
+        flash.set_ota_data_both(1, EspOTAState::Valid);
+        let booted = flash.ota_data(0).to_vec();
+
+        let mut binary = image(1000);
+        binary[0] = 0x00;
+        assert!(matches!(update(&mut flash, &binary), Err(OtaUpdateError::InvalidImageMagic)));
+
+        assert_eq!(flash.ota_data(0), &booted[..]);
+        assert_eq!(get_ota_seq(&mut flash).unwrap(), 1);
+        assert_eq!(get_ota_state(&mut flash).unwrap(), EspOTAState::Valid);
+        assert_eq!(get_booted_partition(&mut flash).unwrap().offset, slot_offset(0));
+    }
+
+    #[test]
+    fn last_chunk_is_padded_to_the_write_size() {
+        let _serial = serial();
+        let mut flash = MockFlash::new(2);
+        // The image is followed by trailing bytes, which the bootloader ignores, up to a length of 4093 bytes
+        let mut binary = image(4000);
+        binary.resize(4093, 0x5A);
+
+        let outcome = update(&mut flash, &binary).unwrap();
+        assert_eq!(outcome.bytes_written, 4093);
+        assert_eq!(flash.slot(0, 4093), &binary[..]);
+        assert_eq!(flash.slot(0, 4096)[4093..], [0xFF; 3]);
+        assert_eq!(get_ota_seq(&mut flash).unwrap(), 1);
+        assert_eq!(get_ota_state(&mut flash).unwrap(), EspOTAState::New);
+        assert_eq!(get_booted_partition(&mut flash).unwrap().offset, slot_offset(0));
+    }
+
+    #[test]
+    fn sequence_number_does_not_wrap_around() {
+        let _serial = serial();
+        let mut flash = MockFlash::new(2);
+        flash.set_ota_data_both(u32::MAX - 1, EspOTAState::Valid);
+        assert!(matches!(
+            update(&mut flash, &image(100)),
+            Err(OtaUpdateError::InternalError(OtaInternalError::SequenceExhausted))
+        ));
+        assert!(matches!(
+            get_next_update_partition(&mut flash),
+            Err(OtaInternalError::SequenceExhausted)
+        ));
+        assert_eq!(get_ota_seq(&mut flash).unwrap(), u32::MAX - 1);
+
+        // The last usable sequence number is `u32::MAX - 1`, the bootloader ignores entries with `u32::MAX`
+        let mut flash = MockFlash::new(2);
+        flash.set_ota_data_both(u32::MAX - 2, EspOTAState::Valid);
+        update(&mut flash, &image(100)).unwrap();
+        assert_eq!(get_ota_seq(&mut flash).unwrap(), u32::MAX - 1);
+        assert_eq!(get_booted_partition(&mut flash).unwrap().offset, slot_offset(((u32::MAX - 2) % 2) as u8));
+    }
+
+    #[test]
+    fn accept_is_refused_while_an_update_is_in_progress() {
+        let _serial = serial();
+        let mut flash = MockFlash::new(2);
+        flash.set_ota_data_both(1, EspOTAState::PendingVerify);
+        let mut other_flash = MockFlash::new(2);
+
+        let writer = ota_begin_chunked(&mut other_flash).unwrap();
+        assert!(matches!(ota_accept(&mut flash), Err(OtaInternalError::AlreadyUpdating)));
+        assert_eq!(get_ota_state(&mut flash).unwrap(), EspOTAState::PendingVerify);
+        drop(writer);
+
+        ota_accept(&mut flash).unwrap();
+        assert_eq!(get_ota_state(&mut flash).unwrap(), EspOTAState::Valid);
+    }
+
+    #[test]
+    fn blank_ota_data_boots_factory_and_updates_ota_0() {
+        for slots in [2, 3] {
+            let mut flash = MockFlash::new(slots);
+            assert_eq!(get_running_ota_slot(&mut flash).unwrap(), None);
+            assert_eq!(get_booted_partition(&mut flash).unwrap().offset, test_utils::FACTORY);
+            assert_eq!(get_next_update_partition(&mut flash).unwrap().offset, slot_offset(0));
+        }
+    }
+
+    #[test]
+    fn blank_ota_data_without_factory_boots_ota_0_and_updates_ota_1() {
+        let _serial = serial();
+        let image = image(1000);
+        for path in ["ota_begin", "ota_begin_chunked", "ota_validate"] {
+            let mut flash = MockFlash::without_factory(2);
+            assert_eq!(get_running_ota_slot(&mut flash).unwrap(), Some(0));
+            assert_eq!(get_booted_partition(&mut flash).unwrap().offset, slot_offset(0));
+            assert_eq!(get_next_update_partition(&mut flash).unwrap().offset, slot_offset(1));
+
+            match path {
+                "ota_begin" => drop(update(&mut flash, &image).unwrap()),
+                "ota_begin_chunked" => {
+                    let mut writer = ota_begin_chunked(&mut flash).unwrap();
+                    writer.write_chunk(&image).unwrap();
+                    writer.finish().unwrap();
+                }
+                _ => {
+                    block_on(ota_validate(&mut flash, SliceReader::new(&image))).unwrap();
+                    continue;
+                }
+            }
+            assert_eq!(flash.slot(1, image.len()), &image[..], "{path}");
+            assert_eq!(flash.slot(0, 4), [0xFF; 4], "{path}");
+            assert_eq!(get_ota_seq(&mut flash).unwrap(), 2, "{path}");
+            assert_eq!(get_running_ota_slot(&mut flash).unwrap(), Some(1), "{path}");
+        }
+    }
+
+    #[test]
+    fn only_committed_updates_increment_the_failure_counter() {
+        let _serial = serial();
+        let layout = OtaLayout {
+            failure_counter: Some("nvs"),
+            ..OtaLayout::default()
+        };
+        let options = || OtaOptions {
+            layout,
+            ..OtaOptions::default()
+        };
+        let mut flash = MockFlash::new(2);
+        let count = |flash: &mut MockFlash| recovery::failure_count(flash, "nvs").unwrap();
+
+        let not_an_image = [0x42; 1000];
+        let result = block_on(ota_begin_with_options(&mut flash, SliceReader::new(&not_an_image), |_| {}, options()));
+        assert!(matches!(result, Err(OtaUpdateError::InvalidImageMagic)));
+        let mut writer = ota_begin_chunked_with_layout(&mut flash, layout).unwrap();
+        writer.write_chunk(&not_an_image).unwrap();
+        assert!(matches!(writer.finish(), Err(OtaUpdateError::InvalidImageMagic)));
+        assert_eq!(count(&mut flash), 0);
+
+        let image = image(1000);
+        block_on(ota_begin_with_options(&mut flash, SliceReader::new(&image), |_| {}, options())).unwrap();
+        assert_eq!(count(&mut flash), 1);
+        ota_accept_with_layout(&mut flash, &layout).unwrap();
+        assert_eq!(count(&mut flash), 0);
+
+        let mut writer = ota_begin_chunked_with_layout(&mut flash, layout).unwrap();
+        writer.write_chunk(&image).unwrap();
+        writer.finish().unwrap();
+        assert_eq!(count(&mut flash), 1);
+    }
+
+    #[test]
+    fn version_store_accepts_higher_versions_and_blocks_lower_ones() {
+        let _serial = serial();
+        let options = || OtaOptions {
+            version_store: Some("nvs"),
+            ..OtaOptions::default()
+        };
+        let mut flash = MockFlash::new(2);
+        let stored = |flash: &mut MockFlash| store::read_value(flash, &esp_partition_table::PartitionTable::default(), "nvs").unwrap();
+
+        for version in [2, 3] {
+            let image = image_with_app_description(version, "1.0.0", "app");
+            block_on(ota_begin_with_options(&mut flash, SliceReader::new(&image), |_| {}, options())).unwrap();
+            ota_accept(&mut flash).unwrap();
+            assert_eq!(stored(&mut flash), Some(version));
+        }
+
+        let image = image_with_app_description(1, "1.0.0", "app");
+        let result = block_on(ota_begin_with_options(&mut flash, SliceReader::new(&image), |_| {}, options()));
+        assert!(matches!(result, Err(OtaUpdateError::DowngradeBlocked { installed: 3, incoming: 1 })));
+        assert_eq!(stored(&mut flash), Some(3));
+        assert_eq!(get_ota_seq(&mut flash).unwrap(), 2);
+    }
+
+    #[test]
+    fn sector_that_was_not_erased_stops_the_update_before_writing_it() {
+        let _serial = serial();
+        let mut flash = MockFlash::new(2);
+        let old_image = [0; 3 * SECTOR_SIZE];
+        flash.set_slot(0, &old_image);
+        flash.stuck_sector = Some(slot_offset(0) + SECTOR_SIZE as u32);
+
+        let image = image(2 * SECTOR_SIZE + 1000);
+        let options = OtaOptions {
+            check_erased: true,
+            ..OtaOptions::default()
+        };
+        let result = block_on(ota_begin_with_options(&mut flash, SliceReader::new(&image), |_| {}, options));
+        assert!(matches!(
+            result,
+            Err(OtaUpdateError::SectorNotErased { offset }) if offset == slot_offset(0) + SECTOR_SIZE as u32
+        ));
+        assert_eq!(flash.slot(0, SECTOR_SIZE), &image[..SECTOR_SIZE]);
+        assert_eq!(flash.slot(0, 2 * SECTOR_SIZE)[SECTOR_SIZE..], old_image[SECTOR_SIZE..2 * SECTOR_SIZE]);
+        assert_eq!(get_ota_seq(&mut flash).unwrap(), 0);
+    }
+
+    #[test]
+    fn running_app_description_is_read_from_the_booted_slot() {
+        let mut flash = MockFlash::new(2);
+        assert!(matches!(running_app_description(&mut flash), Err(OtaInternalError::InvalidAppDescription)));
+
+        flash.set_slot(0, &image_with_app_description(7, "v1.2.3", "my-app"));
+        flash.set_ota_data(0, 1, EspOTAState::Valid);
+        let app = running_app_description(&mut flash).unwrap();
+        assert_eq!(app.secure_version, 7);
+        assert_eq!(app.version(), "v1.2.3");
+        assert_eq!(app.project_name(), "my-app");
+    }
+
+    #[test]
+    fn audit_reports_a_valid_and_a_blank_slot() {
+        let mut flash = MockFlash::new(2);
+        let image = image_with_app_description(1, "v2.0.0", "app");
+        flash.set_slot(0, &image);
+
+        let audit = audit_slots(&mut flash).unwrap();
+        let valid = audit.slots[0].as_ref().unwrap();
+        assert!(valid.has_image());
+        assert_eq!(valid.image_len, Some(image.len()));
+        assert_eq!(valid.app.as_ref().unwrap().version(), "v2.0.0");
+        let blank = audit.slots[1].as_ref().unwrap();
+        assert!(!blank.has_image());
+        assert!(blank.app.is_none());
+        assert!(audit.slots[2..].iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn image_of_another_project_is_rejected() {
+        let _serial = serial();
+        let options = || OtaOptions {
+            expected_project_name: Some("product-b"),
+            ..OtaOptions::default()
+        };
+        let mut flash = MockFlash::new(2);
+
+        let image = image_with_app_description(0, "1.0.0", "product-a");
+        let result = block_on(ota_begin_with_options(&mut flash, SliceReader::new(&image), |_| {}, options()));
+        assert!(matches!(result, Err(OtaUpdateError::ProjectNameMismatch)));
+        assert_eq!(get_ota_seq(&mut flash).unwrap(), 0);
+
+        let image = image_with_app_description(0, "1.0.0", "product-b");
+        block_on(ota_begin_with_options(&mut flash, SliceReader::new(&image), |_| {}, options())).unwrap();
+        assert_eq!(get_ota_seq(&mut flash).unwrap(), 1);
+    }
+
+    #[test]
+    fn partition_is_erased_in_chunks_of_the_configured_size() {
+        let _serial = serial();
+        let image = image(1000);
+        for (sectors, app_erases) in [(None, 1), (Some(16), APP_SIZE / (16 * SECTOR_SIZE)), (Some(0), APP_SIZE / SECTOR_SIZE)] {
+            let mut flash = MockFlash::new(2);
+            let options = OtaOptions {
+                max_erase_chunk_sectors: sectors,
+                ..OtaOptions::default()
+            };
+            block_on(ota_begin_with_options(&mut flash, SliceReader::new(&image), |_| {}, options)).unwrap();
+            // One erase of otadata for the pending entry and one for the commit
+            assert_eq!(flash.erases, app_erases + 2, "{sectors:?}");
+        }
+    }
+
+    #[test]
+    fn running_offset_is_compared_with_the_slot_of_the_otadata() {
+        let mut flash = MockFlash::new(2);
+        flash.set_ota_data(0, 2, EspOTAState::Valid);
+        assert!(running_matches_otadata(&mut flash, slot_offset(1)).unwrap());
+        assert!(running_matches_otadata(&mut flash, slot_offset(1) + 0x1234).unwrap());
+        // The bootloader silently fell back to another slot
+        assert!(!running_matches_otadata(&mut flash, slot_offset(0)).unwrap());
+        assert!(!running_matches_otadata(&mut flash, FACTORY).unwrap());
+    }
+
+    #[test]
+    fn bootloader_app_description_is_read_at_the_bootloader_offset() {
+        let mut flash = MockFlash::new(2);
+        assert!(bootloader_app_description(&mut flash, 0x1000).unwrap().is_none());
+
+        let bootloader = image_with_app_description(0, "v5.1.2", "bootloader");
+        flash.data[0x1000..0x1000 + bootloader.len()].copy_from_slice(&bootloader);
+        let app = bootloader_app_description(&mut flash, 0x1000).unwrap().unwrap();
+        assert_eq!(app.version(), "v5.1.2");
+        assert_eq!(app.project_name(), "bootloader");
+        assert!(bootloader_app_description(&mut flash, 0x0).unwrap().is_none());
+    }
+
+    #[test]
+    fn reset_to_factory_boots_the_factory_app() {
+        let _serial = serial();
+        let mut flash = MockFlash::new(2);
+        flash.set_ota_data_both(3, EspOTAState::Valid);
+        ota_reset_to_factory(&mut flash).unwrap();
+        assert_eq!(get_booted_partition(&mut flash).unwrap().offset, FACTORY);
+        assert_eq!(get_ota_seq(&mut flash).unwrap(), 0);
+
+        let mut flash = MockFlash::without_factory(2);
+        flash.set_ota_data_both(3, EspOTAState::Valid);
+        assert!(matches!(ota_reset_to_factory(&mut flash), Err(OtaInternalError::PartitionNotFound)));
+        assert_eq!(get_ota_seq(&mut flash).unwrap(), 3);
+    }
+
+    #[test]
+    fn flash_that_erases_to_zero_is_checked_and_padded_with_zero() {
+        let _serial = serial();
+        let mut flash = MockFlash::new(2);
+        flash.erased_byte = 0x00;
+        // Not a multiple of the write size, so the last write is padded
+        let image = image(1000);
+        let binary = &image[..image.len() - 3];
+        let options = |erased_byte| OtaOptions {
+            check_erased: true,
+            erased_byte,
+            ..OtaOptions::default()
+        };
+
+        // With the default erased byte the erased sectors look like they were not erased
+        let result = block_on(ota_begin_with_options(&mut flash, SliceReader::new(binary), |_| {}, options(None)));
+        assert!(matches!(result, Err(OtaUpdateError::SectorNotErased { offset }) if offset == slot_offset(0)));
+
+        block_on(ota_begin_with_options(&mut flash, SliceReader::new(binary), |_| {}, options(Some(0x00)))).unwrap();
+        let written = flash.slot(0, image.len());
+        assert_eq!(&written[..binary.len()], binary);
+        assert_eq!(written[binary.len()..], [0x00; 3]);
+        assert_eq!(get_ota_seq(&mut flash).unwrap(), 1);
+    }
+
+    #[test]
+    fn corrupt_first_sector_aborts_before_the_rest_is_written() {
+        let _serial = serial();
+        let mut flash = MockFlash::new(2);
+        flash.set_slot(0, &[0; SECTOR_SIZE]);
+        flash.stuck_sector = Some(slot_offset(0));
+
+        let image = image(3 * SECTOR_SIZE);
+        let options = OtaOptions {
+            verify_first_sector: true,
+            ..OtaOptions::default()
+        };
+        let result = block_on(ota_begin_with_options(&mut flash, SliceReader::new(&image), |_| {}, options));
+        assert!(matches!(result, Err(OtaUpdateError::VerifyMismatch { offset }) if offset == slot_offset(0)));
+        // The pending boot entry and the first sector
+        assert_eq!(flash.writes, 2);
+        assert_eq!(get_ota_seq(&mut flash).unwrap(), 0);
+    }
+
+    #[test]
+    fn update_in_progress_is_reported_until_unlocked() {
+        let _serial = serial();
+        assert!(!is_update_in_progress());
+        // A stuck update, whose future is never polled again nor dropped
+        core::mem::forget(UpdateGuard::acquire().unwrap());
+        assert!(is_update_in_progress());
+        // Querying doesn't clear the flag
+        assert!(is_update_in_progress());
+        ota_force_unlock();
+        assert!(!is_update_in_progress());
+    }
+
+    #[test]
+    fn phases_are_entered_once_in_order() {
+        std::thread_local! {
+            static PHASES: core::cell::RefCell<std::vec::Vec<OtaPhase>> = const { core::cell::RefCell::new(std::vec::Vec::new()) };
+        }
+        fn record(phase: OtaPhase) {
+            PHASES.with_borrow_mut(|phases| phases.push(phase));
+        }
+
+        let _serial = serial();
+        let mut flash = MockFlash::new(2);
+        let image = image(3 * SECTOR_SIZE);
+        let options = OtaOptions {
+            on_phase_change: Some(record),
+            ..OtaOptions::default()
+        };
+        block_on(ota_begin_with_options(&mut flash, SliceReader::new(&image), |_| {}, options)).unwrap();
+        assert_eq!(
+            PHASES.take(),
+            [OtaPhase::Erasing, OtaPhase::Writing, OtaPhase::Verifying, OtaPhase::Finalizing]
+        );
+    }
+
+    #[test]
+    fn unaligned_expected_len_is_padded_and_oversized_one_is_refused_upfront() {
+        let _serial = serial();
+        let mut flash = MockFlash::new(2);
+        let image = image(1000);
+        let binary = &image[..image.len() - 3];
+        let options = |expected_len| OtaOptions {
+            expected_len: Some(expected_len),
+            ..OtaOptions::default()
+        };
+
+        let result = block_on(ota_begin_with_options(&mut flash, SliceReader::new(binary), |_| {}, options(APP_SIZE + 1)));
+        assert!(matches!(result, Err(OtaUpdateError::OutOfSpace { .. })));
+        assert_eq!(flash.erases, 0);
+
+        let outcome =
+            block_on(ota_begin_with_options(&mut flash, SliceReader::new(binary), |_| {}, options(binary.len()))).unwrap();
+        assert_eq!(outcome.bytes_written, binary.len());
+        let written = flash.slot(0, image.len());
+        assert_eq!(&written[..binary.len()], binary);
+        assert_eq!(written[binary.len()..], [0xFF; 3]);
+        assert_eq!(get_ota_seq(&mut flash).unwrap(), 1);
+    }
+
+    #[test]
+    fn slot_written_by_an_update_is_reported_as_live() {
+        let _serial = serial();
+        let mut flash = MockFlash::new(2);
+        let audit = audit_slots(&mut flash).unwrap();
+        assert!(audit.slots.iter().flatten().all(|slot| !slot.live && !slot.has_image()));
+
+        update(&mut flash, &image_with_app_description(0, "v1.0.0", "app")).unwrap();
+        let audit = audit_slots(&mut flash).unwrap();
+        let [Some(populated), Some(empty)] = &audit.slots[..2] else {
+            panic!("both slots are in the table");
+        };
+        assert_eq!(populated.partition.name(), "ota_0");
+        assert!(populated.live && populated.has_image());
+        assert_eq!(populated.app.as_ref().unwrap().version(), "v1.0.0");
+        assert_eq!(empty.partition.name(), "ota_1");
+        assert!(!empty.live && !empty.has_image());
+    }
+
+    #[test]
+    fn rollback_boots_the_previous_slot_again() {
+        let _serial = serial();
+        let mut flash = MockFlash::new(2);
+        update(&mut flash, &image(1000)).unwrap();
+        ota_accept(&mut flash).unwrap();
+        assert!(matches!(ota_rollback(&mut flash), Err(OtaInternalError::NothingToRollBackTo)));
+
+        update(&mut flash, &image(2000)).unwrap();
+        ota_accept(&mut flash).unwrap();
+        ota_rollback_with_layout(&mut flash, &OtaLayout::default()).unwrap();
+        assert_eq!(get_ota_seq(&mut flash).unwrap(), 1);
+        assert_eq!(get_ota_state(&mut flash).unwrap(), EspOTAState::Valid);
+        assert_eq!(get_booted_partition(&mut flash).unwrap().offset, slot_offset(0));
+
+        // The previous slot was overwritten, e.g. by an update that was interrupted
+        let mut flash = MockFlash::new(2);
+        flash.set_ota_data(0, 1, EspOTAState::Valid);
+        flash.set_ota_data(1, 2, EspOTAState::Valid);
+        flash.set_slot(1, &image(1000));
+        assert!(matches!(ota_rollback(&mut flash), Err(OtaInternalError::NothingToRollBackTo)));
+        assert_eq!(get_ota_seq(&mut flash).unwrap(), 2);
+    }
+
+    #[test]
+    fn hooked_variants_apply_the_options() {
+        let _serial = serial();
+        let image = image(1000);
+        let options = || OtaOptions {
+            expected_len: Some(image.len() + 1),
+            ..OtaOptions::default()
+        };
+        let mut flash = MockFlash::new(2);
+        let results = [
+            block_on(ota_begin_with_transform(&mut flash, SliceReader::new(&image), |_| {}, options(), |_, _| {})),
+            block_on(ota_begin_multiwrite(&mut flash, SliceReader::new(&image), |_| {}, options())),
+            block_on(ota_begin_with_timeout(&mut flash, SliceReader::new(&image), |_| {}, options(), core::future::pending)),
+            block_on(ota_begin_signed(&mut flash, SliceReader::new(&image), |_| {}, options(), |_, _| Ok::<(), ()>(()))),
+            block_on(ota_begin_with_clock(&mut flash, SliceReader::new(&image), |_| {}, options(), || 0))
+                .map(|stats| OtaOutcome {
+                    bytes_written: stats.bytes_written,
+                    #[cfg(feature = "sha256")]
+                    sha256: None,
+                }),
+        ];
+        for result in results {
+            assert!(matches!(result, Err(OtaUpdateError::LengthMismatch { .. })), "{result:?}");
+        }
+        assert_eq!(get_ota_seq(&mut flash).unwrap(), 0);
+    }
+
+    #[test]
+    fn ota_status_reads_the_partition_table_once() {
+        let mut flash = MockFlash::new(2);
+        flash.set_ota_data_both(2, EspOTAState::Valid);
+        ParsedPartitions::<STATUS_PARTITIONS>::parse(&mut flash).unwrap();
+        let one_scan = flash.table_reads;
+
+        flash.table_reads = 0;
+        let status = ota_status(&mut flash).unwrap();
+        assert_eq!(flash.table_reads, one_scan);
+        assert_eq!(status.running_slot, Some(1));
+        assert_eq!(status.running_partition.offset, slot_offset(1));
+
+        let layout = OtaLayout {
+            ota_data_name: Some("otadata"),
+            ..OtaLayout::default()
+        };
+        flash.table_reads = 0;
+        assert_eq!(ota_status_with_layout(&mut flash, &layout).unwrap().running_slot, Some(1));
+        assert_eq!(flash.table_reads, one_scan);
+    }
+
+    #[test]
+    fn getters_with_layout_use_the_partition_table_of_the_layout() {
+        let _serial = serial();
+        // Move the partition table into the free sector after the otadata partition
+        let mut flash = MockFlash::new(2);
+        flash.data.copy_within(0x8000..0x9000, 0xf000);
+        flash.data[0x8000..0x9000].fill(0xFF);
+        let layout = OtaLayout {
+            partition_table: Some(esp_partition_table::PartitionTable::new(0xf000, 0xc00)),
+            ..OtaLayout::default()
+        };
+        assert!(get_ota_seq(&mut flash).is_err());
+        assert!(partitions::ota_compatibility_with_layout(&mut flash, &layout).unwrap().is_capable());
+        assert_eq!(next_partition_capacity_with_layout(&mut flash, &layout).unwrap(), test_utils::APP_SIZE);
+        assert!(!fits_in_next_partition_with_layout(&mut flash, &layout, test_utils::APP_SIZE + 1).unwrap());
+
+        let image = image(1000);
+        let options = OtaOptions {
+            layout,
+            ..OtaOptions::default()
+        };
+        block_on(ota_validate_with_layout(&mut flash, &layout, SliceReader::new(&image))).unwrap();
+        block_on(ota_begin_with_options(&mut flash, SliceReader::new(&image), |_| {}, options)).unwrap();
+        assert_eq!(get_ota_seq_with_layout(&mut flash, &layout).unwrap(), 1);
+        assert_eq!(get_ota_state_with_layout(&mut flash, &layout).unwrap(), EspOTAState::New);
+        assert!(!is_pending_verify_with_layout(&mut flash, &layout).unwrap());
+        assert!(is_slot_bootable_with_layout(&mut flash, &layout, 0).unwrap());
+        assert!(running_matches_otadata_with_layout(&mut flash, &layout, slot_offset(0)).unwrap());
+        assert!(audit_slots_with_layout(&mut flash, &layout).unwrap().slots[0].as_ref().unwrap().live);
+        assert_eq!(
+            ota_check_consistency_with_layout(&mut flash, &layout, InconsistencyPolicy::Error).unwrap().offset,
+            slot_offset(0)
+        );
+
+        assert!(matches!(
+            ota_accept_seq_with_layout(&mut flash, &layout, 2),
+            Err(OtaInternalError::SequenceMismatch { expected: 2, actual: 1 })
+        ));
+        ota_accept_seq_with_layout(&mut flash, &layout, 1).unwrap();
+        assert_eq!(get_ota_state_with_layout(&mut flash, &layout).unwrap(), EspOTAState::Valid);
+        ota_reject_seq_with_layout(&mut flash, &layout, 1).unwrap();
+        ota_clear_version_store_with_layout(&mut flash, &layout, "nvs").unwrap();
+
+        assert_eq!(erase_ota_data_with_layout(&mut flash, &layout).unwrap().seq, 0);
+        assert_eq!(get_ota_seq_with_layout(&mut flash, &layout).unwrap(), 0);
+    }
+
+    #[test]
+    fn running_app_without_factory_is_ota_0_while_the_ota_data_is_blank() {
+        let mut flash = MockFlash::without_factory(2);
+        flash.set_slot(0, &image(1000));
+
+        let status = ota_status(&mut flash).unwrap();
+        assert_eq!(status.running_slot, Some(0));
+        assert_eq!(status.running_partition.offset, slot_offset(0));
+        assert_eq!(status.seq, 0);
+        assert!(matches!(running_app_description(&mut flash), Err(OtaInternalError::InvalidAppDescription)));
+        assert_eq!(
+            ota_check_consistency(&mut flash, InconsistencyPolicy::Error).unwrap().offset,
+            slot_offset(0)
+        );
+
+        // If only the other slot contains an image, the fallback must pick the entry that boots it
+        let mut flash = MockFlash::without_factory(2);
+        flash.set_slot(1, &image(1000));
+        assert_eq!(
+            ota_check_consistency(&mut flash, InconsistencyPolicy::UseOtherSlot).unwrap().offset,
+            slot_offset(1)
+        );
+        assert_eq!(get_booted_partition(&mut flash).unwrap().offset, slot_offset(1));
+    }
+
+    #[cfg(feature = "async-flash")]
+    #[test]
+    fn async_update_without_factory_does_not_erase_the_running_ota_0() {
+        let _serial = serial();
+        let image = image(1000);
+        let mut flash = MockFlash::without_factory(2);
+        block_on(async_flash::ota_begin_async(&mut flash, SliceReader::new(&image), |_| {})).unwrap();
+        assert_eq!(flash.slot(1, image.len()), &image[..]);
+        assert_eq!(get_ota_seq(&mut flash).unwrap(), 2);
+
+        let mut flash = MockFlash::without_factory(1);
+        assert!(matches!(
+            block_on(async_flash::ota_begin_async(&mut flash, SliceReader::new(&image), |_| {})),
+            Err(error::AsyncOtaUpdateError::WouldEraseRunningPartition)
+        ));
+    }
+
+    #[cfg(feature = "async-flash")]
+    #[test]
+    fn async_update_with_layout_uses_the_partition_table_and_counter_of_the_layout() {
+        let _serial = serial();
+        let mut flash = MockFlash::new(2);
+        flash.data.copy_within(0x8000..0x9000, 0xf000);
+        flash.data[0x8000..0x9000].fill(0xFF);
+        let layout = OtaLayout {
+            partition_table: Some(esp_partition_table::PartitionTable::new(0xf000, 0xc00)),
+            failure_counter: Some("nvs"),
+            ..OtaLayout::default()
+        };
+        let image = image(1000);
+        assert!(block_on(async_flash::ota_begin_async(&mut flash, SliceReader::new(&image), |_| {})).is_err());
+
+        block_on(async_flash::ota_begin_async_with_layout(&mut flash, SliceReader::new(&image), |_| {}, &layout))
+            .unwrap();
+        assert_eq!(flash.slot(0, image.len()), &image[..]);
+        assert_eq!(block_on(async_flash::read_ota_data_async_with_layout(&mut flash, &layout)).unwrap().seq, 1);
+        assert_eq!(get_ota_seq_with_layout(&mut flash, &layout).unwrap(), 1);
+        assert_eq!(recovery::failure_count_at(&mut flash, &layout.table(), "nvs").unwrap(), 1);
+    }
+
+    #[cfg(feature = "async-flash")]
+    #[test]
+    fn async_update_retries_failed_reads() {
+        let _serial = serial();
+        let image = image(1000);
+        let mut flash = MockFlash::new(2);
+        flash.failing_reads = 1;
+        assert!(block_on(async_flash::ota_begin_async(&mut flash, SliceReader::new(&image), |_| {})).is_err());
+
+        set_flash_retries(1);
+        flash.failing_reads = 1;
+        let result = block_on(async_flash::ota_begin_async(&mut flash, SliceReader::new(&image), |_| {}));
+        set_flash_retries(0);
+        result.unwrap();
+        assert_eq!(get_ota_seq(&mut flash).unwrap(), 1);
+    }
+
+    #[cfg(feature = "async-flash")]
+    #[test]
+    fn async_update_reports_how_much_did_not_fit() {
+        let _serial = serial();
+        let binary = std::vec![crate::ESP_IMAGE_MAGIC; test_utils::APP_SIZE + 1];
+        let mut flash = MockFlash::new(2);
+        assert!(matches!(
+            block_on(async_flash::ota_begin_async(&mut flash, SliceReader::new(&binary), |_| {})),
+            Err(error::AsyncOtaUpdateError::OutOfSpace {
+                partition_size: test_utils::APP_SIZE,
+                bytes_written,
+            }) if bytes_written == test_utils::APP_SIZE + 1
+        ));
+    }
+
+    #[test]
+    fn single_slot_without_factory_would_erase_the_running_app() {
+        let _serial = serial();
+        let mut flash = MockFlash::without_factory(1);
+        let image = image(1000);
+        assert!(matches!(update(&mut flash, &image), Err(OtaUpdateError::WouldEraseRunningPartition)));
+        assert!(matches!(ota_begin_chunked(&mut flash), Err(OtaUpdateError::WouldEraseRunningPartition)));
+        assert_eq!(flash.erases, 0);
+    }
+}
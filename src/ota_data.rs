@@ -1,56 +1,462 @@
 use crate::error::OtaInternalError;
-use crate::ota_data_structs::EspOTAData;
-use crate::partitions::find_partition_by_type;
-use crate::SECTOR_SIZE;
-use embedded_storage::nor_flash::NorFlash;
-use esp_partition_table::{DataPartitionType, NorFlashOpError, PartitionType};
-
-/// Read from ota data partition
-pub fn read_ota_data<S: NorFlash>(storage: &mut S) -> Result<EspOTAData, OtaInternalError<S>> {
-    let ota_data_part =
-        find_partition_by_type(storage, PartitionType::Data(DataPartitionType::Ota))?;
-    let mut buffer = [0; 32];
+use crate::ota_data_structs::{EspOTAData, EspOTAState};
+use crate::partitions::{find_ota_data_partition, OtaLayout};
+use crate::{retry_flash, SECTOR_SIZE};
+use core::sync::atomic::Ordering;
+use embedded_storage::nor_flash::{MultiwriteNorFlash, NorFlash};
+use esp_partition_table::{AppPartitionType, NorFlashOpError, PartitionEntry, PartitionType};
+use portable_atomic::AtomicUsize;
 
-    storage
-        .read(ota_data_part.offset, &mut buffer)
-        .map_err(|e| NorFlashOpError::StorageError(e))?;
-    if let Ok(data) = EspOTAData::try_from(buffer) {
-        return Ok(data);
+/// Read from ota data partition, selecting the copy the bootloader boots (see `select_ota_data`).
+/// If both copies are erased, e.g. on first boot, returns `EspOTAData::blank`.
+/// The same is returned if no copy may be booted, e.g. after the first update failed or was rejected,
+/// since the bootloader then also boots as if the ota data were blank.
+/// Returns `OtaDataCorrupt` only if neither copy is valid and at least one of them contains data.
+pub fn read_ota_data<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+) -> Result<EspOTAData, OtaInternalError<S>> {
+    let ota_data_part = ota_data_partition(storage, layout)?;
+    let mut copies = read_ota_data_copies(storage, &ota_data_part)?;
+
+    match select_ota_data(&copies) {
+        Some(selected) if is_bootable(copies[selected].as_ref().unwrap()) => {
+            Ok(copies[selected].take().unwrap())
+        }
+        Some(_) => Ok(EspOTAData::blank()),
+        None if ota_data_is_blank(storage, &ota_data_part)? => Ok(EspOTAData::blank()),
+        None => Err(OtaInternalError::OtaDataCorrupt),
+    }
+}
+
+/// Find the otadata partition and check that it fits both copies.
+/// The bootloader always reads the second copy `SECTOR_SIZE` after the first, independent of the partition size,
+/// so the copies are not spread over a larger partition, but a partition that is too small is rejected
+/// instead of reading past its end.
+fn ota_data_partition<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+) -> Result<PartitionEntry, OtaInternalError<S>> {
+    let ota_data_part = find_ota_data_partition(storage, layout)?;
+    if ota_data_part.size < 2 * SECTOR_SIZE {
+        return Err(OtaInternalError::OtaDataTooSmall {
+            size: ota_data_part.size,
+        });
     }
+    Ok(ota_data_part)
+}
 
-    storage
-        .read(ota_data_part.offset + SECTOR_SIZE as u32, &mut buffer)
+/// Returns true if both copies of the ota data are erased
+fn ota_data_is_blank<S: NorFlash>(
+    storage: &mut S,
+    ota_data_part: &PartitionEntry,
+) -> Result<bool, OtaInternalError<S>> {
+    for copy in 0..2 {
+        let mut buffer = [0; 32];
+        let offset = ota_data_part.offset + copy * SECTOR_SIZE as u32;
+        retry_flash(|| storage.read(offset, &mut buffer))
+            .map_err(|e| NorFlashOpError::StorageError(e))?;
+        if buffer != [0xFF; 32] {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Read both copies of the ota data, a copy is `None` if it is corrupt
+fn read_ota_data_copies<S: NorFlash>(
+    storage: &mut S,
+    ota_data_part: &PartitionEntry,
+) -> Result<[Option<EspOTAData>; 2], OtaInternalError<S>> {
+    Ok([
+        read_ota_data_copy(storage, ota_data_part, 0)?,
+        read_ota_data_copy(storage, ota_data_part, 1)?,
+    ])
+}
+
+/// Read one of the two copies of the ota data, returns `None` if the copy is corrupt
+fn read_ota_data_copy<S: NorFlash>(
+    storage: &mut S,
+    ota_data_part: &PartitionEntry,
+    copy: u32,
+) -> Result<Option<EspOTAData>, OtaInternalError<S>> {
+    let mut buffer = [0; 32];
+    let offset = ota_data_part.offset + copy * SECTOR_SIZE as u32;
+    retry_flash(|| storage.read(offset, &mut buffer))
         .map_err(|e| NorFlashOpError::StorageError(e))?;
-    EspOTAData::try_from(buffer).map_err(|_| OtaInternalError::OtaDataCorrupt)
+    Ok(EspOTAData::try_from(buffer).ok())
+}
+
+/// Select the index of the copy the bootloader boots, like `bootloader_common_get_active_otadata`.
+/// The precedence is:
+/// 1. Copies with a bad CRC or sequence number `u32::MAX` are never selected, like `bootloader_common_ota_select_valid`.
+/// 2. Copies that are not invalid or aborted are preferred, the copy with the highest sequence number among them is selected.
+/// 3. If neither copy may be booted, e.g. because the update was rejected, the copy with the highest sequence number.
+///    The bootloader boots as if the ota data were blank in that case, which `read_ota_data` reports,
+///    but the copy is still where a state change of that entry is written, see `target_copy`.
+/// 4. If both copies have the same sequence number after the above, e.g. because a state change was interrupted,
+///    the first copy is selected, like the bootloader does.
+///
+/// So of two copies with the same sequence number where one is `PendingVerify` and the other `Aborted`,
+/// the `PendingVerify` copy is selected. Returns `None` if both copies are corrupt.
+pub(crate) fn select_ota_data(copies: &[Option<EspOTAData>; 2]) -> Option<usize> {
+    let highest = |only_bootable: bool| {
+        copies
+            .iter()
+            .enumerate()
+            // `max_by_key` returns the last maximum, so iterate from the back to prefer the first copy on a tie
+            .rev()
+            .filter_map(|(i, data)| Some((i, data.as_ref()?)))
+            .filter(|(_, data)| data.seq != u32::MAX)
+            .filter(|(_, data)| !only_bootable || is_bootable(data))
+            .max_by_key(|(_, data)| data.seq)
+            .map(|(i, _)| i)
+    };
+    highest(true).or_else(|| highest(false))
+}
+
+/// Returns false for entries the bootloader never boots, like `bootloader_common_ota_select_invalid`
+pub(crate) fn is_bootable(data: &EspOTAData) -> bool {
+    !matches!(data.state, EspOTAState::Invalid | EspOTAState::Aborted)
+}
+
+/// Rewrite a corrupt copy of the ota data from the other copy, restoring the redundancy.
+/// Returns true if a copy was rewritten, false if both copies are readable or both are erased.
+pub fn repair_ota_data<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+) -> Result<bool, OtaInternalError<S>> {
+    let ota_data_part = ota_data_partition(storage, layout)?;
+
+    match read_ota_data_copies(storage, &ota_data_part)? {
+        [Some(_), Some(_)] => Ok(false),
+        [Some(data), None] => {
+            write_ota_data_copy(storage, &ota_data_part, 1, data, false).map(|_| true)
+        }
+        [None, Some(data)] => {
+            write_ota_data_copy(storage, &ota_data_part, 0, data, false).map(|_| true)
+        }
+        [None, None] if ota_data_is_blank(storage, &ota_data_part)? => Ok(false),
+        [None, None] => Err(OtaInternalError::OtaDataCorrupt),
+    }
 }
 
-/// Write to ota data partition
+/// Write a boot entry to the ota data partition.
+/// Only one copy is written, see `target_copy`, so the other copy keeps the previous entry as fallback.
 pub fn write_ota_data<S: NorFlash>(
     storage: &mut S,
+    layout: &OtaLayout,
+    data: EspOTAData,
+) -> Result<(), OtaInternalError<S>> {
+    write_ota_data_inner(storage, layout, data, false)
+}
+
+/// Write a boot entry to the ota data partition like `write_ota_data`, but without erasing the copy first
+/// if the new entry only clears bits of the entry that is there, which `MultiwriteNorFlash` allows.
+/// This is the case when an update is committed over its pending entry (`Aborted` to `New`).
+/// Skipping the erase saves an erase cycle and closes the window in which a power loss leaves the copy blank.
+pub fn write_ota_data_multiwrite<S: MultiwriteNorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+    data: EspOTAData,
+) -> Result<(), OtaInternalError<S>> {
+    write_ota_data_inner(storage, layout, data, true)
+}
+
+/// Write a boot entry, writing it in place if `in_place` is set and the flash allows it, see `write_ota_data_copy`
+fn write_ota_data_inner<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+    data: EspOTAData,
+    in_place: bool,
+) -> Result<(), OtaInternalError<S>> {
+    let ota_data_part = ota_data_partition(storage, layout)?;
+    let copies = read_ota_data_copies(storage, &ota_data_part)?;
+    let old = select_ota_data(&copies).and_then(|selected| copies[selected].clone());
+
+    let copy = target_copy(&copies, data.seq);
+    write_ota_data_copy(storage, &ota_data_part, copy, data.clone(), in_place)?;
+
+    if let Some(hook) = transition_hook() {
+        hook(OtaTransition {
+            old_seq: old.as_ref().map(|old| old.seq),
+            new_seq: data.seq,
+            old_state: old.as_ref().map(|old| old.state),
+            new_state: data.state,
+        });
+    }
+    Ok(())
+}
+
+/// Write the entry of an update that is still being written.
+/// The entry is marked as aborted, which neither the bootloader nor this crate will select,
+/// and it is written to the copy that `read_ota_data` does not use, which is where it will be committed.
+/// Until the entry is committed with `write_ota_data`, the old entry stays the one that is booted.
+pub fn write_pending_ota_data<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+    seq: u32,
+) -> Result<(), OtaInternalError<S>> {
+    let ota_data_part = ota_data_partition(storage, layout)?;
+    let copies = read_ota_data_copies(storage, &ota_data_part)?;
+
+    let mut data = EspOTAData::new(seq, [0xFF; 20]);
+    data.state = EspOTAState::Aborted;
+    write_ota_data_copy(storage, &ota_data_part, target_copy(&copies, seq), data, false)
+}
+
+/// The copy an entry with sequence number `seq` is written to, like `esp_rewrite_ota_data` in esp-idf.
+/// A state change of the selected entry overwrites it in place,
+/// a new entry is written to the other copy so the selected entry keeps being available as fallback.
+/// Since sequence numbers increase by one per update, new entries alternate between the copies.
+pub(crate) fn target_copy(copies: &[Option<EspOTAData>; 2], seq: u32) -> u32 {
+    match select_ota_data(copies) {
+        Some(selected) if copies[selected].as_ref().is_some_and(|data| data.seq == seq) => {
+            selected as u32
+        }
+        Some(selected) => 1 - selected as u32,
+        None => seq.wrapping_sub(1) % 2,
+    }
+}
+
+/// Erase and write one of the two copies of the ota data.
+/// With `in_place`, which may only be set for a `MultiwriteNorFlash`, the erase is skipped
+/// if the new entry can be written by only clearing bits of the current contents.
+fn write_ota_data_copy<S: NorFlash>(
+    storage: &mut S,
+    ota_data_part: &PartitionEntry,
+    copy: u32,
     data: EspOTAData,
+    in_place: bool,
 ) -> Result<(), OtaInternalError<S>> {
-    let ota_data_part =
-        find_partition_by_type(storage, PartitionType::Data(DataPartitionType::Ota))?;
     let buffer: [u8; 32] = data.into();
+    let offset = ota_data_part.offset + copy * SECTOR_SIZE as u32;
+    if !(offset as usize).is_multiple_of(S::WRITE_SIZE) || !buffer.len().is_multiple_of(S::WRITE_SIZE) {
+        return Err(OtaInternalError::Misaligned { offset });
+    }
+
+    if in_place {
+        let mut current = [0; 32];
+        retry_flash(|| storage.read(offset, &mut current))
+            .map_err(|e| NorFlashOpError::StorageError(e))?;
+        if current.iter().zip(&buffer).all(|(current, new)| current & new == *new) {
+            retry_flash(|| storage.write(offset, &buffer))
+                .map_err(|e| NorFlashOpError::StorageError(e))?;
+            return Ok(());
+        }
+    }
 
-    // Write sector A
-    storage
-        .erase(ota_data_part.offset, ota_data_part.offset + SECTOR_SIZE as u32)
+    retry_flash(|| storage.erase(offset, offset + SECTOR_SIZE as u32))
         .map_err(|e| NorFlashOpError::StorageError(e))?;
-    storage
-        .write(ota_data_part.offset, &buffer)
+    retry_flash(|| storage.write(offset, &buffer))
         .map_err(|e| NorFlashOpError::StorageError(e))?;
 
-    // Write sector B
-    storage
-        .erase(
-            ota_data_part.offset + SECTOR_SIZE as u32,
-            ota_data_part.offset + 2 * SECTOR_SIZE as u32,
-        )
-        .map_err(|e| NorFlashOpError::StorageError(e))?;
-    storage
-        .write(ota_data_part.offset + SECTOR_SIZE as u32, &buffer)
+    Ok(())
+}
+
+/// Erase both copies of the ota data, after which the bootloader boots the factory app
+pub fn reset_to_factory<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+) -> Result<(), OtaInternalError<S>> {
+    layout.find_partition_by_type(storage, PartitionType::App(AppPartitionType::Factory))?;
+    erase_ota_data(storage, layout)
+}
+
+/// Erase both copies of the ota data, after which the ota data reads as `EspOTAData::blank`
+pub fn erase_ota_data<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+) -> Result<(), OtaInternalError<S>> {
+    let ota_data_part = ota_data_partition(storage, layout)?;
+
+    let offset = ota_data_part.offset;
+    retry_flash(|| storage.erase(offset, offset + 2 * SECTOR_SIZE as u32))
         .map_err(|e| NorFlashOpError::StorageError(e))?;
 
     Ok(())
 }
+
+/// A record of a change to the boot entry in the ota data, for machine-parseable logging.
+/// See `set_transition_hook`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct OtaTransition {
+    /// Sequence number before the change, `None` if the ota data was not readable
+    pub old_seq: Option<u32>,
+    /// Sequence number after the change
+    pub new_seq: u32,
+    /// State before the change, `None` if the ota data was not readable
+    pub old_state: Option<EspOTAState>,
+    /// State after the change
+    pub new_state: EspOTAState,
+}
+
+/// The registered hook, stored as its address since there is no atomic for function pointers. 0 means no hook.
+static TRANSITION_HOOK: AtomicUsize = AtomicUsize::new(0);
+
+// The address must be able to hold a function pointer without truncating it
+const _: () = assert!(core::mem::size_of::<fn(OtaTransition)>() == core::mem::size_of::<usize>());
+
+/// Register a hook that is called with a structured record every time the boot entry in the ota data is written,
+/// in addition to the free-text log messages. Pass `None` to remove the hook.
+pub fn set_transition_hook(hook: Option<fn(OtaTransition)>) {
+    TRANSITION_HOOK.store(hook.map_or(0, |hook| hook as usize), Ordering::SeqCst);
+}
+
+pub(crate) fn transition_hook() -> Option<fn(OtaTransition)> {
+    let address = TRANSITION_HOOK.load(Ordering::SeqCst);
+    // SAFETY: the only non-zero values ever stored are addresses of `fn(OtaTransition)`, which have the same size
+    (address != 0).then(|| unsafe { core::mem::transmute::<usize, fn(OtaTransition)>(address) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    fn read(flash: &mut MockFlash) -> Result<EspOTAData, OtaInternalError<MockFlash>> {
+        read_ota_data(flash, &OtaLayout::default())
+    }
+
+    #[test]
+    fn copy_with_highest_seq_is_selected() {
+        for (a, b) in [(4, 5), (5, 4)] {
+            let mut flash = MockFlash::new(2);
+            flash.set_ota_data(0, a, EspOTAState::Valid);
+            flash.set_ota_data(1, b, EspOTAState::Valid);
+            assert_eq!(read(&mut flash).unwrap().seq(), 5);
+        }
+    }
+
+    #[test]
+    fn copy_that_may_not_be_booted_is_skipped() {
+        for state in [EspOTAState::Invalid, EspOTAState::Aborted] {
+            let mut flash = MockFlash::new(2);
+            flash.set_ota_data(0, 4, EspOTAState::Valid);
+            flash.set_ota_data(1, 5, state);
+            assert_eq!(read(&mut flash).unwrap().seq(), 4);
+        }
+    }
+
+    /// Flip a bit of the CRC of copy `copy`
+    fn corrupt(flash: &mut MockFlash, copy: usize) {
+        flash.data[OTA_DATA as usize + copy * SECTOR_SIZE + 28] ^= 1;
+    }
+
+    #[test]
+    fn blank_ota_data_reads_as_blank() {
+        let mut flash = MockFlash::new(2);
+        let data = read(&mut flash).unwrap();
+        assert_eq!((data.seq(), data.state()), (0, EspOTAState::Undefined));
+        assert!(data.is_valid());
+    }
+
+    #[test]
+    fn one_corrupt_copy_falls_back_to_the_other() {
+        for copy in [0, 1] {
+            let mut flash = MockFlash::new(2);
+            flash.set_ota_data(0, 4, EspOTAState::Valid);
+            flash.set_ota_data(1, 5, EspOTAState::Valid);
+            corrupt(&mut flash, copy);
+            let expected = if copy == 0 { 5 } else { 4 };
+            assert_eq!(read(&mut flash).unwrap().seq(), expected);
+        }
+
+        // A corrupt copy next to an erased one is corruption, not blank ota data
+        let mut flash = MockFlash::new(2);
+        flash.set_ota_data(0, 4, EspOTAState::Valid);
+        corrupt(&mut flash, 0);
+        assert!(matches!(read(&mut flash), Err(OtaInternalError::OtaDataCorrupt)));
+    }
+
+    #[test]
+    fn both_corrupt_copies_are_an_error() {
+        let mut flash = MockFlash::new(2);
+        flash.set_ota_data_both(4, EspOTAState::Valid);
+        corrupt(&mut flash, 0);
+        corrupt(&mut flash, 1);
+        assert!(matches!(read(&mut flash), Err(OtaInternalError::OtaDataCorrupt)));
+    }
+
+    #[test]
+    fn tie_prefers_the_copy_that_is_not_aborted() {
+        for pending in [0, 1] {
+            let mut flash = MockFlash::new(2);
+            flash.set_ota_data(pending, 5, EspOTAState::PendingVerify);
+            flash.set_ota_data(1 - pending, 5, EspOTAState::Aborted);
+            assert_eq!(read(&mut flash).unwrap().state(), EspOTAState::PendingVerify);
+        }
+    }
+
+    #[test]
+    fn tie_between_bootable_copies_prefers_the_first_copy() {
+        let mut flash = MockFlash::new(2);
+        flash.set_ota_data(0, 5, EspOTAState::Valid);
+        flash.set_ota_data(1, 5, EspOTAState::PendingVerify);
+        assert_eq!(read(&mut flash).unwrap().state(), EspOTAState::Valid);
+    }
+
+    #[test]
+    fn copy_with_seq_u32_max_is_ignored() {
+        let mut flash = MockFlash::new(2);
+        flash.set_ota_data(0, 4, EspOTAState::Valid);
+        flash.set_ota_data(1, u32::MAX, EspOTAState::Valid);
+        assert_eq!(read(&mut flash).unwrap().seq(), 4);
+    }
+
+    #[test]
+    fn ota_data_without_bootable_copy_reads_as_blank() {
+        let mut flash = MockFlash::new(2);
+        flash.set_ota_data(1, 1, EspOTAState::Aborted);
+        let data = read(&mut flash).unwrap();
+        assert_eq!((data.seq(), data.state()), (0, EspOTAState::Undefined));
+    }
+
+    #[test]
+    fn repair_rewrites_the_corrupt_copy_from_the_other() {
+        let mut flash = MockFlash::new(2);
+        flash.set_ota_data(0, 4, EspOTAState::Valid);
+        flash.set_ota_data(1, 5, EspOTAState::Valid);
+        corrupt(&mut flash, 0);
+
+        assert!(repair_ota_data(&mut flash, &OtaLayout::default()).unwrap());
+        assert_eq!(flash.ota_data(0), flash.ota_data(1));
+        let partition = ota_data_partition(&mut flash, &OtaLayout::default()).unwrap();
+        let copies = read_ota_data_copies(&mut flash, &partition).unwrap();
+        assert!(copies.iter().all(|copy| copy.as_ref().is_some_and(|data| data.seq() == 5)));
+        assert!(!repair_ota_data(&mut flash, &OtaLayout::default()).unwrap());
+    }
+
+    #[test]
+    fn transition_hook_records_the_commit_and_the_accept() {
+        std::thread_local! {
+            static RECORDED: core::cell::RefCell<std::vec::Vec<OtaTransition>> = const { core::cell::RefCell::new(std::vec::Vec::new()) };
+        }
+        fn record(transition: OtaTransition) {
+            RECORDED.with_borrow_mut(|recorded| recorded.push(transition));
+        }
+
+        let _serial = serial();
+        let mut flash = MockFlash::new(2);
+        flash.set_ota_data_both(1, EspOTAState::Valid);
+        set_transition_hook(Some(record));
+        update(&mut flash, &image(1000)).unwrap();
+        crate::ota_accept(&mut flash).unwrap();
+        set_transition_hook(None);
+        crate::ota_reject(&mut flash).unwrap();
+
+        let transition = |old_seq, old_state, new_state| OtaTransition {
+            old_seq: Some(old_seq),
+            new_seq: 2,
+            old_state: Some(old_state),
+            new_state,
+        };
+        assert_eq!(
+            RECORDED.take(),
+            [
+                transition(1, EspOTAState::Valid, EspOTAState::New),
+                transition(2, EspOTAState::New, EspOTAState::Valid),
+            ]
+        );
+    }
+}
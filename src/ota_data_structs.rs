@@ -1,4 +1,4 @@
-use crate::crc::esp_crc32;
+use crate::crc::ota_data_crc;
 use core::fmt::{Display, Formatter};
 
 /// Copied from esp-idf
@@ -9,6 +9,7 @@ use core::fmt::{Display, Formatter};
 /// -`Aborted`: App could not confirm the workable or non-workable. In bootloader IMG_PENDING_VERIFY state will be changed to IMG_ABORTED. This app will not be selected to boot at all.
 /// -`Undefined`: Undefined. App can boot and work without limits.
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum EspOTAState {
     New,
     PendingVerify,
@@ -47,7 +48,19 @@ impl From<EspOTAState> for u32 {
     }
 }
 
+/// Reason an otadata entry is rejected
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum OtaDataError {
+    /// The CRC does not match the sequence number, e.g. because the entry is erased
+    BadCrc,
+    /// The state field of an entry with a valid CRC does not contain a known `EspOTAState`
+    UnknownState(u32),
+}
+
+/// One of the two copies of the boot entry in the otadata partition (`esp_ota_select_entry_t`)
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct EspOTAData {
     pub(crate) seq: u32,
     pub(crate) label: [u8; 20],
@@ -58,7 +71,7 @@ pub struct EspOTAData {
 impl EspOTAData {
     pub(crate) fn new(seq: u32, label: [u8; 20]) -> Self {
         let state = EspOTAState::New;
-        let crc = esp_crc32(&seq.to_le_bytes());
+        let crc = ota_data_crc(seq);
         Self {
             seq,
             label,
@@ -67,6 +80,40 @@ impl EspOTAData {
         }
     }
 
+    /// The state of an ota data partition that was never written, e.g. on first boot or after `ota_reset_to_factory`.
+    /// The bootloader then boots the factory app, or `ota_0` if the partition table has no factory app,
+    /// and the first update is written to `ota_0`, or `ota_1` without a factory app.
+    pub(crate) fn blank() -> Self {
+        Self {
+            seq: 0,
+            label: [0xFF; 20],
+            state: EspOTAState::Undefined,
+            crc: u32::MAX,
+        }
+    }
+
+    /// Check whether the 32 bytes of an otadata entry would be accepted by this crate, without touching flash
+    pub fn validate(bytes: &[u8; 32]) -> Result<(), OtaDataError> {
+        Self::try_from(*bytes).map(|_| ())
+    }
+
+    /// Sequence number of the entry, the bootloader boots ota app partition `(seq - 1) % <amount of ota partitions>`.
+    /// Zero if the ota data was never written, in which case the factory app is booted.
+    pub fn seq(&self) -> u32 {
+        self.seq
+    }
+
+    /// The 20 byte `seq_label` field of the entry, see `OtaOptions::label`.
+    /// All `0xFF` unless the entry was written with a label.
+    pub fn label(&self) -> &[u8; 20] {
+        &self.label
+    }
+
+    /// State of the entry
+    pub fn state(&self) -> EspOTAState {
+        self.state
+    }
+
     /// Returns true if this OTA update has been accepted, i.e. with `ota_accept`
     pub fn is_valid(&self) -> bool {
         self.state == EspOTAState::Valid || self.state == EspOTAState::Undefined
@@ -84,23 +131,24 @@ impl Display for EspOTAData {
 }
 
 impl TryFrom<[u8; 32]> for EspOTAData {
-    type Error = ();
+    type Error = OtaDataError;
     fn try_from(value: [u8; 32]) -> Result<Self, Self::Error> {
         let seq = u32::from_le_bytes(value[0..4].try_into().unwrap());
         let label = value[4..24].try_into().unwrap();
-        let state =
-            EspOTAState::try_from(u32::from_le_bytes(value[24..28].try_into().unwrap())).unwrap();
+        // The CRC is checked first, so an entry that is erased or otherwise not written is `BadCrc`
+        // regardless of what its state field contains
         let crc = u32::from_le_bytes(value[28..32].try_into().unwrap());
-        if crc == esp_crc32(&seq.to_le_bytes()) {
-            Ok(Self {
-                seq,
-                label,
-                state,
-                crc,
-            })
-        } else {
-            Err(())
+        if crc != ota_data_crc(seq) {
+            return Err(OtaDataError::BadCrc);
         }
+        let state = u32::from_le_bytes(value[24..28].try_into().unwrap());
+        let state = EspOTAState::try_from(state).map_err(|()| OtaDataError::UnknownState(state))?;
+        Ok(Self {
+            seq,
+            label,
+            state,
+            crc,
+        })
     }
 }
 
@@ -110,8 +158,55 @@ impl From<EspOTAData> for [u8; 32] {
         ret[0..4].copy_from_slice(&value.seq.to_le_bytes());
         ret[4..24].copy_from_slice(&value.label);
         ret[24..28].copy_from_slice(&u32::to_le_bytes(value.state.into()));
-        let crc = esp_crc32(&value.seq.to_le_bytes());
+        let crc = ota_data_crc(value.seq);
         ret[28..32].copy_from_slice(&crc.to_le_bytes());
         ret
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_round_trips_through_bytes() {
+        let mut label = [0xFF; 20];
+        label[..5].copy_from_slice(b"v1.23");
+        let mut data = EspOTAData::new(7, label);
+        data.state = EspOTAState::PendingVerify;
+
+        let bytes: [u8; 32] = data.clone().into();
+        let parsed = EspOTAData::try_from(bytes).unwrap();
+        assert_eq!(parsed.seq(), 7);
+        assert_eq!(parsed.label(), &label);
+        assert_eq!(parsed.state(), EspOTAState::PendingVerify);
+        assert_eq!(bytes[28..32], ota_data_crc(7).to_le_bytes());
+        assert_eq!(EspOTAData::validate(&bytes), Ok(()));
+    }
+
+    #[test]
+    fn entry_written_by_esp_idf_is_parsed() {
+        let mut bytes = [0xFF; 32];
+        bytes[0..4].copy_from_slice(&1u32.to_le_bytes());
+        bytes[24..28].copy_from_slice(&2u32.to_le_bytes());
+        bytes[28..32].copy_from_slice(&[0x9a, 0x98, 0x43, 0x47]);
+        let parsed = EspOTAData::try_from(bytes).unwrap();
+        assert_eq!((parsed.seq(), parsed.state()), (1, EspOTAState::Valid));
+    }
+
+    #[test]
+    fn bad_crc_and_unknown_state_are_rejected() {
+        let mut bytes: [u8; 32] = EspOTAData::new(7, [0xFF; 20]).into();
+        bytes[28] ^= 1;
+        assert_eq!(EspOTAData::validate(&bytes), Err(OtaDataError::BadCrc));
+
+        let mut bytes: [u8; 32] = EspOTAData::new(7, [0xFF; 20]).into();
+        bytes[24..28].copy_from_slice(&9u32.to_le_bytes());
+        assert_eq!(EspOTAData::validate(&bytes), Err(OtaDataError::UnknownState(9)));
+
+        // Garbage in the state field of an entry with a bad CRC is still reported as a bad CRC
+        bytes[28] ^= 1;
+        assert_eq!(EspOTAData::validate(&bytes), Err(OtaDataError::BadCrc));
+        assert_eq!(EspOTAData::validate(&[0xFF; 32]), Err(OtaDataError::BadCrc));
+    }
+}
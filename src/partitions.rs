@@ -1,14 +1,173 @@
+use crate::crc::crc32_digest;
 use crate::error::OtaInternalError;
-use crate::error::OtaInternalError::{NorFlashOpError, PartitionFoundTwice, PartitionNotFound};
+use crate::retry_flash;
+use crate::error::OtaInternalError::{
+    NoOtaDataPartition, NorFlashOpError, OutOfBounds, PartitionFoundTwice, PartitionNotFound,
+    TooManyPartitions,
+};
 use embedded_storage::nor_flash::NorFlash;
-use esp_partition_table::{PartitionEntry, PartitionTable, PartitionType};
+use esp_partition_table::{
+    AppPartitionType, DataPartitionType, PartitionEntry, PartitionTable, PartitionType,
+};
+
+/// Describes where to find the partitions used for OTA, for partition tables that deviate from the defaults
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OtaLayout<'a> {
+    /// Name of the otadata partition.
+    /// If no partition with this name exists, the otadata partition is found by its type instead.
+    pub ota_data_name: Option<&'a str>,
+    /// Name of a data partition that counts updates that were installed but never accepted,
+    /// see `recovery::check_failure_threshold`.
+    /// Updates increment the counter once they write the new boot entry, `ota_accept` resets it when it accepts a pending update.
+    pub failure_counter: Option<&'a str>,
+    /// Location of the partition table, for bootloaders that place it elsewhere than the default offset of 0x8000
+    pub partition_table: Option<PartitionTable>,
+    /// The entries of the partition table, parsed once with `ParsedPartitions` and then searched
+    /// instead of reading the partition table from flash again for every lookup. These must be the entries of the
+    /// `partition_table`. The partitions named by `ota_data_name` and `failure_counter` are still found there.
+    pub partitions: Option<&'a [PartitionEntry]>,
+}
+
+impl OtaLayout<'_> {
+    /// The partition table to search, the default table if the layout doesn't specify one
+    pub fn table(&self) -> PartitionTable {
+        self.partition_table.unwrap_or_default()
+    }
+
+    /// Find partition entry by type, in the parsed partitions if the layout has them
+    pub(crate) fn find_partition_by_type<S: NorFlash>(
+        &self,
+        storage: &mut S,
+        typ: PartitionType,
+    ) -> Result<PartitionEntry, OtaInternalError<S>> {
+        let Some(partitions) = self.partitions else {
+            return find_partition_by_type_at(storage, &self.table(), typ);
+        };
+        let mut matches = partitions.iter().filter(|entry| entry.type_ == typ);
+        let found_partition = matches.next().ok_or(PartitionNotFound)?;
+        if matches.next().is_some() {
+            return Err(PartitionFoundTwice);
+        }
+        Ok(found_partition.clone())
+    }
+
+    /// Find partition entry by name, in the parsed partitions if the layout has them
+    pub(crate) fn find_partition_by_name<S: NorFlash>(
+        &self,
+        storage: &mut S,
+        name: &str,
+    ) -> Result<PartitionEntry, OtaInternalError<S>> {
+        let Some(partitions) = self.partitions else {
+            return find_partition_by_name_at(storage, &self.table(), name);
+        };
+        let mut matches = partitions.iter().filter(|entry| entry.name() == name);
+        let found_partition = matches.next().ok_or(PartitionNotFound)?;
+        if matches.next().is_some() {
+            return Err(PartitionFoundTwice);
+        }
+        Ok(found_partition.clone())
+    }
+
+    /// Find all ota app partitions, indexed by their ota number, in the parsed partitions if the layout has them
+    pub(crate) fn find_ota_partitions<S: NorFlash>(
+        &self,
+        storage: &mut S,
+    ) -> Result<[Option<PartitionEntry>; MAX_OTA_PARTITIONS], OtaInternalError<S>> {
+        let Some(entries) = self.partitions else {
+            return find_ota_partitions_at(storage, &self.table());
+        };
+        let mut partitions = [const { None }; MAX_OTA_PARTITIONS];
+        for entry in entries {
+            if let PartitionType::App(AppPartitionType::Ota(n)) = entry.type_ {
+                let slot = &mut partitions[n as usize];
+                if slot.is_some() {
+                    return Err(PartitionFoundTwice);
+                }
+                *slot = Some(entry.clone());
+            }
+        }
+        Ok(partitions)
+    }
+
+    /// Count the ota app partitions, in the parsed partitions if the layout has them
+    pub(crate) fn count_ota_partitions<S: NorFlash>(
+        &self,
+        storage: &mut S,
+    ) -> Result<usize, OtaInternalError<S>> {
+        let Some(partitions) = self.partitions else {
+            return count_ota_partitions_at(storage, &self.table());
+        };
+        Ok(partitions
+            .iter()
+            .filter(|entry| matches!(entry.type_, PartitionType::App(AppPartitionType::Ota(_))))
+            .count())
+    }
+}
+
+/// The entries of a partition table, read from flash once so a whole update can reuse them, see `OtaLayout::partitions`.
+/// Holds up to `N` entries without allocating, the default table of esp-idf has fewer than 8.
+/// The partition table must not change while the parsed entries are in use.
+#[derive(Debug, Clone)]
+pub struct ParsedPartitions<const N: usize> {
+    table: PartitionTable,
+    entries: [PartitionEntry; N],
+    len: usize,
+}
+
+impl<const N: usize> ParsedPartitions<N> {
+    /// Read the partition table at the default location.
+    /// Returns `TooManyPartitions` if the table has more than `N` entries.
+    pub fn parse<S: NorFlash>(storage: &mut S) -> Result<Self, OtaInternalError<S>> {
+        Self::parse_at(storage, &PartitionTable::default())
+    }
+
+    /// Read the partition table at a non-default location, see `parse`
+    pub fn parse_at<S: NorFlash>(
+        storage: &mut S,
+        table: &PartitionTable,
+    ) -> Result<Self, OtaInternalError<S>> {
+        let mut entries = core::array::from_fn(|_| PartitionEntry::default());
+        let len = collect_partitions_into(storage, table, &mut entries)?;
+        if len > N {
+            return Err(TooManyPartitions { count: len });
+        }
+        Ok(Self {
+            table: *table,
+            entries,
+            len,
+        })
+    }
+
+    /// The entries in the order of the partition table
+    pub fn entries(&self) -> &[PartitionEntry] {
+        &self.entries[..self.len]
+    }
+
+    /// A default layout that uses these entries instead of reading the partition table,
+    /// e.g. `OtaOptions { layout: parsed.layout(), ..Default::default() }`
+    pub fn layout(&self) -> OtaLayout<'_> {
+        OtaLayout {
+            partition_table: Some(self.table),
+            partitions: Some(self.entries()),
+            ..OtaLayout::default()
+        }
+    }
+}
 
 /// Find partition entry by type
 pub fn find_partition_by_type<S: NorFlash>(
     storage: &mut S,
     typ: PartitionType,
 ) -> Result<PartitionEntry, OtaInternalError<S>> {
-    let table = PartitionTable::default();
+    find_partition_by_type_at(storage, &PartitionTable::default(), typ)
+}
+
+/// Find partition entry by type, in the partition table at a non-default location
+pub fn find_partition_by_type_at<S: NorFlash>(
+    storage: &mut S,
+    table: &PartitionTable,
+    typ: PartitionType,
+) -> Result<PartitionEntry, OtaInternalError<S>> {
     let mut found_partition = None;
 
     for entry in table.iter_nor_flash(storage, false) {
@@ -30,7 +189,15 @@ pub fn find_partition_by_name<S: NorFlash>(
     storage: &mut S,
     name: &str
 ) -> Result<PartitionEntry, OtaInternalError<S>> {
-    let table = PartitionTable::default();
+    find_partition_by_name_at(storage, &PartitionTable::default(), name)
+}
+
+/// Find partition entry by name, in the partition table at a non-default location
+pub fn find_partition_by_name_at<S: NorFlash>(
+    storage: &mut S,
+    table: &PartitionTable,
+    name: &str,
+) -> Result<PartitionEntry, OtaInternalError<S>> {
     let mut found_partition = None;
 
     for entry in table.iter_nor_flash(storage, false) {
@@ -45,4 +212,520 @@ pub fn find_partition_by_name<S: NorFlash>(
     }
 
     found_partition.ok_or(PartitionNotFound)
-}
\ No newline at end of file
+}
+
+/// Find partition entry by the raw type and subtype bytes, e.g. for user-defined types (0x40 to 0xFE)
+/// or to match a subtype without constructing the `PartitionType`.
+/// Like the other finders, this returns an error if the table contains an entry `esp-partition-table` can't parse,
+/// e.g. an app or data partition with an unknown subtype.
+pub fn find_partition_by_subtype<S: NorFlash>(
+    storage: &mut S,
+    major_type: u8,
+    subtype: u8,
+) -> Result<PartitionEntry, OtaInternalError<S>> {
+    find_partition_by_subtype_with_layout(storage, &OtaLayout::default(), major_type, subtype)
+}
+
+/// Find partition entry by the raw type and subtype bytes, in the partition table of the layout.
+/// See `find_partition_by_subtype`.
+pub fn find_partition_by_subtype_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+    major_type: u8,
+    subtype: u8,
+) -> Result<PartitionEntry, OtaInternalError<S>> {
+    let is_match = |entry: &PartitionEntry| <(u8, u8)>::try_from(entry.type_) == Ok((major_type, subtype));
+
+    if let Some(partitions) = layout.partitions {
+        let mut matches = partitions.iter().filter(|entry| is_match(entry));
+        let found_partition = matches.next().ok_or(PartitionNotFound)?;
+        if matches.next().is_some() {
+            return Err(PartitionFoundTwice);
+        }
+        return Ok(found_partition.clone());
+    }
+
+    let mut found_partition = None;
+
+    for entry in layout.table().iter_nor_flash(storage, false) {
+        let entry = entry.map_err(NorFlashOpError)?;
+        if is_match(&entry) {
+            if found_partition.is_none() {
+                found_partition = Some(entry);
+            } else {
+                return Err(PartitionFoundTwice);
+            }
+        }
+    }
+
+    found_partition.ok_or(PartitionNotFound)
+}
+
+/// List all entries of the partition table, e.g. for a diagnostic that shows the full table.
+/// The entries are stored in `entries` in the order of the table, the remaining elements are set to `None`.
+/// Returns the amount of entries in the table, which is larger than `entries.len()` if not all of them fit.
+pub fn list_partitions<S: NorFlash>(
+    storage: &mut S,
+    entries: &mut [Option<PartitionEntry>],
+) -> Result<usize, OtaInternalError<S>> {
+    list_partitions_at(storage, &PartitionTable::default(), entries)
+}
+
+/// List all entries of the partition table at a non-default location, see `list_partitions`
+pub fn list_partitions_at<S: NorFlash>(
+    storage: &mut S,
+    table: &PartitionTable,
+    entries: &mut [Option<PartitionEntry>],
+) -> Result<usize, OtaInternalError<S>> {
+    collect_partitions(storage, table, entries, |_| true)
+}
+
+/// Find all partition entries of a type, like `find_partition_by_type` but allowing multiple matches.
+/// The matches are stored in `entries` like `list_partitions` does, returns the amount of matches.
+pub fn find_all_partitions_by_type<S: NorFlash>(
+    storage: &mut S,
+    typ: PartitionType,
+    entries: &mut [Option<PartitionEntry>],
+) -> Result<usize, OtaInternalError<S>> {
+    collect_partitions(storage, &PartitionTable::default(), entries, |entry| {
+        entry.type_ == typ
+    })
+}
+
+/// Store all entries of the table in `entries`, returns the amount of entries in the table
+fn collect_partitions_into<S: NorFlash>(
+    storage: &mut S,
+    table: &PartitionTable,
+    entries: &mut [PartitionEntry],
+) -> Result<usize, OtaInternalError<S>> {
+    let mut count = 0;
+
+    for entry in table.iter_nor_flash(storage, false) {
+        let entry = entry.map_err(NorFlashOpError)?;
+        if let Some(slot) = entries.get_mut(count) {
+            *slot = entry;
+        }
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+fn collect_partitions<S: NorFlash>(
+    storage: &mut S,
+    table: &PartitionTable,
+    entries: &mut [Option<PartitionEntry>],
+    filter: impl Fn(&PartitionEntry) -> bool,
+) -> Result<usize, OtaInternalError<S>> {
+    entries.fill(None);
+    let mut count = 0;
+
+    for entry in table.iter_nor_flash(storage, false) {
+        let entry = entry.map_err(NorFlashOpError)?;
+        if filter(&entry) {
+            if let Some(slot) = entries.get_mut(count) {
+                *slot = Some(entry);
+            }
+            count += 1;
+        }
+    }
+
+    Ok(count)
+}
+
+/// Maximum amount of ota app partitions in a partition table (`ota_0` to `ota_15`)
+pub const MAX_OTA_PARTITIONS: usize = 16;
+
+/// Find all ota app partitions, indexed by their ota number
+pub fn find_ota_partitions<S: NorFlash>(
+    storage: &mut S,
+) -> Result<[Option<PartitionEntry>; MAX_OTA_PARTITIONS], OtaInternalError<S>> {
+    find_ota_partitions_at(storage, &PartitionTable::default())
+}
+
+/// Find all ota app partitions, in the partition table at a non-default location
+pub fn find_ota_partitions_at<S: NorFlash>(
+    storage: &mut S,
+    table: &PartitionTable,
+) -> Result<[Option<PartitionEntry>; MAX_OTA_PARTITIONS], OtaInternalError<S>> {
+    let mut partitions = [const { None }; MAX_OTA_PARTITIONS];
+
+    for entry in table.iter_nor_flash(storage, false) {
+        let entry = entry.map_err(NorFlashOpError)?;
+        if let PartitionType::App(AppPartitionType::Ota(n)) = entry.type_ {
+            let slot = &mut partitions[n as usize];
+            if slot.is_some() {
+                return Err(PartitionFoundTwice);
+            }
+            *slot = Some(entry);
+        }
+    }
+
+    Ok(partitions)
+}
+
+/// Count the ota app partitions, the bootloader cycles through this many slots
+pub fn count_ota_partitions<S: NorFlash>(storage: &mut S) -> Result<usize, OtaInternalError<S>> {
+    count_ota_partitions_at(storage, &PartitionTable::default())
+}
+
+/// Count the ota app partitions, in the partition table at a non-default location
+pub fn count_ota_partitions_at<S: NorFlash>(
+    storage: &mut S,
+    table: &PartitionTable,
+) -> Result<usize, OtaInternalError<S>> {
+    let mut count = 0;
+
+    for entry in table.iter_nor_flash(storage, false) {
+        let entry = entry.map_err(NorFlashOpError)?;
+        if let PartitionType::App(AppPartitionType::Ota(_)) = entry.type_ {
+            count += 1;
+        }
+    }
+
+    Ok(count)
+}
+
+/// Find the otadata partition in the partition table of the layout, by name if the layout specifies one and otherwise by type.
+/// Returns `NoOtaDataPartition` if there is none, e.g. on a device with a partition table for only a factory app.
+pub fn find_ota_data_partition<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+) -> Result<PartitionEntry, OtaInternalError<S>> {
+    if let Some(name) = layout.ota_data_name {
+        match layout.find_partition_by_name(storage, name) {
+            Err(PartitionNotFound) => {}
+            result => return result,
+        }
+    }
+    match layout.find_partition_by_type(storage, PartitionType::Data(DataPartitionType::Ota)) {
+        Err(PartitionNotFound) => Err(NoOtaDataPartition),
+        result => result,
+    }
+}
+
+/// Read the raw MD5 checksum stored in the partition table, the 16 bytes after the `0xEBEB` marker entry.
+/// Returns `None` if the table has no MD5 entry, e.g. when it was generated with `--disable-md5sum`.
+/// The checksum is returned as stored, it is not checked against the table.
+pub fn partition_table_md5<S: NorFlash>(
+    storage: &mut S,
+) -> Result<Option<[u8; 16]>, OtaInternalError<S>> {
+    partition_table_md5_with_layout(storage, &OtaLayout::default())
+}
+
+/// Read the raw MD5 checksum stored in the partition table of the layout, see `partition_table_md5`.
+/// The table is always read from flash, the parsed `partitions` of the layout don't include the checksum.
+pub fn partition_table_md5_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+) -> Result<Option<[u8; 16]>, OtaInternalError<S>> {
+    let table = layout.table();
+    let mut iter = table.iter_nor_flash(storage, false);
+
+    for entry in &mut iter {
+        entry.map_err(NorFlashOpError)?;
+    }
+
+    Ok(iter.stored_md5().copied())
+}
+
+/// Compute the CRC32 of the raw partition table region, to cheaply detect any change to the table between reads.
+/// Unlike `partition_table_md5`, this covers the full region including unused entries.
+pub fn partition_table_crc<S: NorFlash>(storage: &mut S) -> Result<u32, OtaInternalError<S>> {
+    partition_table_crc_with_layout(storage, &OtaLayout::default())
+}
+
+/// Compute the CRC32 of the raw partition table region of the layout, see `partition_table_crc`
+pub fn partition_table_crc_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+) -> Result<u32, OtaInternalError<S>> {
+    let table = layout.table();
+    let mut digest = crc32_digest();
+
+    let mut buffer = [0; 256];
+    for offset in (table.addr..table.addr + table.size as u32).step_by(buffer.len()) {
+        retry_flash(|| storage.read(offset, &mut buffer))
+            .map_err(|e| NorFlashOpError(esp_partition_table::NorFlashOpError::StorageError(e)))?;
+        digest.update(&buffer);
+    }
+
+    Ok(digest.finalize())
+}
+
+/// Find the range of flash sectors a partition occupies, as the index of the first sector and the amount of sectors.
+/// Sectors are `S::ERASE_SIZE` bytes large.
+pub fn partition_sectors<S: NorFlash>(
+    storage: &mut S,
+    name: &str,
+) -> Result<(u32, u32), OtaInternalError<S>> {
+    partition_sectors_with_layout(storage, &OtaLayout::default(), name)
+}
+
+/// Find the range of flash sectors a partition of the layout occupies, see `partition_sectors`
+pub fn partition_sectors_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+    name: &str,
+) -> Result<(u32, u32), OtaInternalError<S>> {
+    let partition = layout.find_partition_by_name(storage, name)?;
+    let first = partition.offset as usize / S::ERASE_SIZE;
+    let end = (partition.offset as usize + partition.size).div_ceil(S::ERASE_SIZE);
+    Ok((first as u32, (end - first) as u32))
+}
+
+/// Read `buf.len()` bytes at `offset_within` the partition with this name, e.g. config in a custom data partition.
+/// Returns `OutOfBounds` if the range doesn't lie entirely within the partition, nothing is read in that case.
+pub fn read_partition<S: NorFlash>(
+    storage: &mut S,
+    name: &str,
+    offset_within: u32,
+    buf: &mut [u8],
+) -> Result<(), OtaInternalError<S>> {
+    read_partition_with_layout(storage, &OtaLayout::default(), name, offset_within, buf)
+}
+
+/// Read `buf.len()` bytes at `offset_within` the partition of the layout with this name, see `read_partition`
+pub fn read_partition_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+    name: &str,
+    offset_within: u32,
+    buf: &mut [u8],
+) -> Result<(), OtaInternalError<S>> {
+    let partition = layout.find_partition_by_name(storage, name)?;
+    let end = (offset_within as usize).checked_add(buf.len());
+    if end.is_none_or(|end| end > partition.size) {
+        return Err(OutOfBounds);
+    }
+    retry_flash(|| storage.read(partition.offset + offset_within, buf))
+        .map_err(|e| NorFlashOpError(esp_partition_table::NorFlashOpError::StorageError(e)))
+}
+
+/// Alignment the bootloader requires for app partitions, so they can be mapped by the MMU
+const APP_ALIGNMENT: u32 = 0x10000;
+
+/// Whether the partition table supports OTA updates with this crate, see `ota_compatibility`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct OtaCompatibility {
+    /// Exactly one otadata partition exists
+    pub has_ota_data: bool,
+    /// The otadata partition fits both copies of the boot entry, i.e. it is at least two sectors large
+    pub ota_data_size_ok: bool,
+    /// Amount of consecutively numbered ota app partitions, starting at `ota_0`
+    pub ota_slots: usize,
+    /// A factory app partition exists, which is required for `ota_reset_to_factory`
+    pub has_factory: bool,
+    /// Amount of ota app partitions that are not aligned to 64 KiB, which the bootloader can't boot
+    pub misaligned_slots: usize,
+}
+
+impl OtaCompatibility {
+    /// Returns true if there are no blocking issues, i.e. updates can be installed and booted
+    pub fn is_capable(&self) -> bool {
+        self.has_ota_data
+            && self.ota_data_size_ok
+            && self.ota_slots >= 2
+            && self.misaligned_slots == 0
+    }
+}
+
+/// Check whether OTA updates are possible with the partition table, and if not, why.
+/// Only errors if the partition table can't be read.
+pub fn ota_compatibility<S: NorFlash>(
+    storage: &mut S,
+) -> Result<OtaCompatibility, OtaInternalError<S>> {
+    ota_compatibility_with_layout(storage, &OtaLayout::default())
+}
+
+/// Check whether OTA updates are possible with the partition table of the layout, see `ota_compatibility`
+pub fn ota_compatibility_with_layout<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+) -> Result<OtaCompatibility, OtaInternalError<S>> {
+    let (has_ota_data, ota_data_size_ok) =
+        match find_ota_data_partition(storage, layout) {
+            Ok(partition) => (true, partition.size >= 2 * crate::SECTOR_SIZE),
+            Err(NoOtaDataPartition | PartitionFoundTwice) => (false, false),
+            Err(e) => return Err(e),
+        };
+
+    let slots = match layout.find_ota_partitions(storage) {
+        Ok(slots) => slots,
+        Err(PartitionFoundTwice) => [const { None }; MAX_OTA_PARTITIONS],
+        Err(e) => return Err(e),
+    };
+    let ota_slots = slots.iter().take_while(|slot| slot.is_some()).count();
+    let misaligned_slots = slots
+        .iter()
+        .flatten()
+        .filter(|slot| slot.offset % APP_ALIGNMENT != 0)
+        .count();
+
+    let has_factory =
+        match layout.find_partition_by_type(storage, PartitionType::App(AppPartitionType::Factory)) {
+            Ok(_) => true,
+            Err(PartitionNotFound | PartitionFoundTwice) => false,
+            Err(e) => return Err(e),
+        };
+
+    Ok(OtaCompatibility {
+        has_ota_data,
+        ota_data_size_ok,
+        ota_slots,
+        has_factory,
+        misaligned_slots,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn read_partition_refuses_reads_past_the_end() {
+        let mut flash = MockFlash::new(2);
+        let mut buf = [0; 4];
+        read_partition(&mut flash, "nvs", 0x4000 - 4, &mut buf).unwrap();
+        assert!(matches!(read_partition(&mut flash, "nvs", 0x4000 - 3, &mut buf), Err(OutOfBounds)));
+        assert!(matches!(read_partition(&mut flash, "nvs", u32::MAX, &mut buf), Err(OutOfBounds)));
+    }
+
+    #[test]
+    fn md5_entry_of_the_table_is_returned() {
+        let mut flash = MockFlash::new(2);
+        assert_eq!(partition_table_md5(&mut flash).unwrap(), None);
+
+        // The entry after the last partition, as written by gen_esp32part.py
+        let md5 = [
+            0x2c, 0x9f, 0x0a, 0x6e, 0x41, 0xd3, 0x87, 0x15, 0xb2, 0x60, 0xe8, 0x3b, 0x74, 0xc1, 0x5d, 0x09,
+        ];
+        let offset = 0x8000 + 5 * 32;
+        flash.data[offset..offset + 2].copy_from_slice(&[0xEB, 0xEB]);
+        flash.data[offset + 2..offset + 16].fill(0xFF);
+        flash.data[offset + 16..offset + 32].copy_from_slice(&md5);
+        assert_eq!(partition_table_md5(&mut flash).unwrap(), Some(md5));
+    }
+
+    #[test]
+    fn ota_data_partition_is_found_by_a_custom_name() {
+        let mut flash = MockFlash::new(2);
+        let entry = PartitionEntry::new(
+            PartitionType::Data(DataPartitionType::Undefined),
+            OTA_DATA,
+            0x2000,
+            "boot_sel",
+            false,
+        );
+        flash.set_partition(1, entry.unwrap());
+        flash.set_ota_data(0, 3, crate::EspOTAState::Valid);
+
+        assert!(matches!(
+            find_ota_data_partition(&mut flash, &OtaLayout::default()),
+            Err(NoOtaDataPartition)
+        ));
+        let layout = OtaLayout {
+            ota_data_name: Some("boot_sel"),
+            ..OtaLayout::default()
+        };
+        assert_eq!(find_ota_data_partition(&mut flash, &layout).unwrap().offset, OTA_DATA);
+        assert_eq!(crate::get_ota_seq_with_layout(&mut flash, &layout).unwrap(), 3);
+    }
+
+    #[test]
+    fn partition_sectors_round_up_a_partial_sector() {
+        let mut flash = MockFlash::new(2);
+        // nvs is at 0x9000 and 0x4000 bytes large
+        assert_eq!(partition_sectors(&mut flash, "nvs").unwrap(), (9, 4));
+
+        let entry = PartitionEntry::new(
+            PartitionType::Data(DataPartitionType::Undefined),
+            0x9000,
+            0x1800,
+            "nvs",
+            false,
+        );
+        flash.set_partition(0, entry.unwrap());
+        assert_eq!(partition_sectors(&mut flash, "nvs").unwrap(), (9, 2));
+    }
+
+    #[test]
+    fn table_crc_changes_with_the_table() {
+        let mut flash = MockFlash::new(2);
+        let crc = partition_table_crc(&mut flash).unwrap();
+        assert_eq!(partition_table_crc(&mut flash).unwrap(), crc);
+
+        let entry = PartitionEntry::new(
+            PartitionType::Data(DataPartitionType::Nvs),
+            0x9000,
+            0x3000,
+            "nvs",
+            false,
+        );
+        flash.set_partition(0, entry.unwrap());
+        let resized = partition_table_crc(&mut flash).unwrap();
+        assert_ne!(resized, crc);
+
+        // Also bytes after the last entry are covered
+        flash.data[0x8000 + 0xbff] = 0;
+        assert_ne!(partition_table_crc(&mut flash).unwrap(), resized);
+    }
+
+    #[test]
+    fn compatibility_reports_what_the_table_lacks() {
+        let mut flash = MockFlash::new(2);
+        let capable = ota_compatibility(&mut flash).unwrap();
+        assert_eq!(
+            capable,
+            OtaCompatibility {
+                has_ota_data: true,
+                ota_data_size_ok: true,
+                ota_slots: 2,
+                has_factory: true,
+                misaligned_slots: 0,
+            }
+        );
+        assert!(capable.is_capable());
+
+        // A factory app is optional
+        let compatibility = ota_compatibility(&mut MockFlash::without_factory(2)).unwrap();
+        assert!(!compatibility.has_factory && compatibility.is_capable());
+
+        let compatibility = ota_compatibility(&mut MockFlash::new(1)).unwrap();
+        assert_eq!(compatibility.ota_slots, 1);
+        assert!(!compatibility.is_capable());
+
+        let mut flash = MockFlash::new(2);
+        let small = PartitionEntry::new(
+            PartitionType::Data(DataPartitionType::Ota),
+            OTA_DATA,
+            0x1000,
+            "otadata",
+            false,
+        );
+        flash.set_partition(1, small.unwrap());
+        let compatibility = ota_compatibility(&mut flash).unwrap();
+        assert!(compatibility.has_ota_data && !compatibility.ota_data_size_ok);
+        assert!(!compatibility.is_capable());
+
+        let mut flash = MockFlash::new(2);
+        let other = PartitionEntry::new(
+            PartitionType::Data(DataPartitionType::Undefined),
+            OTA_DATA,
+            0x2000,
+            "otadata",
+            false,
+        );
+        flash.set_partition(1, other.unwrap());
+        assert!(!ota_compatibility(&mut flash).unwrap().has_ota_data);
+
+        // esp-partition-table refuses to write a misaligned app partition, so move ota_1 in the raw entry
+        let mut flash = MockFlash::new(2);
+        let ota_1_offset = 0x8000 + 4 * 32 + 4;
+        flash.data[ota_1_offset..ota_1_offset + 4].copy_from_slice(&(slot_offset(1) + 0x1000).to_le_bytes());
+        let compatibility = ota_compatibility(&mut flash).unwrap();
+        assert_eq!(compatibility.misaligned_slots, 1);
+        assert!(!compatibility.is_capable());
+    }
+}
@@ -0,0 +1,201 @@
+//! Breaking boot loops caused by updates that keep failing.
+//!
+//! The failure counter is stored in the first erase sector (`S::ERASE_SIZE`) of a dedicated data partition, configured with
+//! `OtaLayout::failure_counter`. This partition must not be one of the app partitions or otadata,
+//! so the counter survives updates and rollbacks. A small (one sector) partition of any data subtype is sufficient.
+//! The same holds for the boot counter used by `accept_after_boots`.
+
+use crate::error::OtaInternalError;
+use crate::ota_data::{read_ota_data, reset_to_factory};
+use crate::ota_data_structs::EspOTAState;
+use crate::partitions::OtaLayout;
+use crate::store;
+use embedded_storage::nor_flash::NorFlash;
+use esp_partition_table::PartitionTable;
+
+/// Read the amount of updates that were installed but never accepted
+pub fn failure_count<S: NorFlash>(
+    storage: &mut S,
+    failure_counter: &str,
+) -> Result<u32, OtaInternalError<S>> {
+    failure_count_at(storage, &PartitionTable::default(), failure_counter)
+}
+
+/// Read the failure counter, in the partition table at a non-default location
+pub fn failure_count_at<S: NorFlash>(
+    storage: &mut S,
+    table: &PartitionTable,
+    failure_counter: &str,
+) -> Result<u32, OtaInternalError<S>> {
+    Ok(store::read_value(storage, table, failure_counter)?.unwrap_or(0))
+}
+
+/// Increment the failure counter, this is done by `ota_begin` when it writes the new boot entry and a failure counter is configured
+pub fn increment_failure_count<S: NorFlash>(
+    storage: &mut S,
+    failure_counter: &str,
+) -> Result<(), OtaInternalError<S>> {
+    increment_failure_count_at(storage, &PartitionTable::default(), failure_counter)
+}
+
+/// Increment the failure counter, in the partition table at a non-default location
+pub fn increment_failure_count_at<S: NorFlash>(
+    storage: &mut S,
+    table: &PartitionTable,
+    failure_counter: &str,
+) -> Result<(), OtaInternalError<S>> {
+    let count = failure_count_at(storage, table, failure_counter)?;
+    store::write_value(storage, table, failure_counter, count.saturating_add(1))
+}
+
+/// Reset the failure counter, this is done by `ota_accept` when a failure counter is configured.
+/// The counter is only erased if it is non-zero, so this doesn't wear the flash on every boot.
+pub fn reset_failure_count<S: NorFlash>(
+    storage: &mut S,
+    failure_counter: &str,
+) -> Result<(), OtaInternalError<S>> {
+    reset_failure_count_at(storage, &PartitionTable::default(), failure_counter)
+}
+
+/// Reset the failure counter, in the partition table at a non-default location
+pub fn reset_failure_count_at<S: NorFlash>(
+    storage: &mut S,
+    table: &PartitionTable,
+    failure_counter: &str,
+) -> Result<(), OtaInternalError<S>> {
+    if store::read_value(storage, table, failure_counter)?.is_some() {
+        store::clear_value(storage, table, failure_counter)?;
+    }
+    Ok(())
+}
+
+/// Should be called at boot, before `ota_accept`.
+/// If the failure counter of the layout has reached `threshold`, the ota data is erased so the bootloader boots the
+/// factory app on the next boot, and the counter is reset. Returns true if this happened, the caller should then reboot.
+/// Returns `PartitionNotFound` if the partition table has no factory app partition.
+pub fn check_failure_threshold<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+    threshold: u32,
+) -> Result<bool, OtaInternalError<S>> {
+    let Some(failure_counter) = layout.failure_counter else {
+        return Ok(false);
+    };
+    let table = layout.table();
+    let count = failure_count_at(storage, &table, failure_counter)?;
+    if count < threshold {
+        return Ok(false);
+    }
+
+    warn!("{count} OTA updates failed in a row, rolling back to the factory app.");
+    reset_to_factory(storage, layout)?;
+    reset_failure_count_at(storage, &table, failure_counter)?;
+    Ok(true)
+}
+
+/// Should be called at boot instead of `ota_accept`, once the app considers the boot successful.
+/// A new update is only accepted once this has been called on `boots` boots of it, counted in the `boot_counter`
+/// data partition. Returns true if the running app is accepted.
+///
+/// Counting boots requires a bootloader with rollback disabled, which leaves the entry of an update in the `New` state.
+/// With rollback enabled the bootloader marks the entry `PendingVerify` on the first boot and aborts it on the
+/// second boot that did not accept it, so there is only one boot to decide on: the update is accepted right away.
+pub fn accept_after_boots<S: NorFlash>(
+    storage: &mut S,
+    layout: &OtaLayout,
+    boot_counter: &str,
+    boots: u32,
+) -> Result<bool, OtaInternalError<S>> {
+    let table = layout.table();
+    let ota_data = read_ota_data(storage, layout)?;
+    if !matches!(ota_data.state, EspOTAState::PendingVerify | EspOTAState::New) {
+        // Not pending (anymore), so a counter left by an update that was rolled back must not count for the next one
+        if store::read_value(storage, &table, boot_counter)?.is_some() {
+            store::clear_value(storage, &table, boot_counter)?;
+        }
+        return Ok(ota_data.is_valid());
+    }
+
+    let count = store::read_value(storage, &table, boot_counter)?.unwrap_or(0).saturating_add(1);
+    if count < boots {
+        if ota_data.state == EspOTAState::New {
+            info!("Boot {count} of {boots} of the new OTA update, not accepting yet.");
+            store::write_value(storage, &table, boot_counter, count)?;
+            return Ok(false);
+        }
+        warn!("The bootloader has rollback enabled, accepting the OTA update on its first boot.");
+    }
+
+    crate::ota_accept_with_layout(storage, layout)?;
+    store::clear_value(storage, &table, boot_counter)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{block_on, image, serial, update, MockFlash, SliceReader};
+    use crate::{get_ota_seq, ota_accept_with_layout, ota_begin_with_options, EspOTAData, OtaOptions};
+
+    #[test]
+    fn update_is_accepted_after_the_configured_amount_of_boots() {
+        let _serial = serial();
+        let layout = OtaLayout::default();
+        let image = image(1000);
+        let mut flash = MockFlash::new(2);
+
+        // Without rollback the entry stays `New` on every boot
+        update(&mut flash, &image).unwrap();
+        assert!(!accept_after_boots(&mut flash, &layout, "nvs", 3).unwrap());
+        assert!(!accept_after_boots(&mut flash, &layout, "nvs", 3).unwrap());
+        assert_eq!(store::read_value(&mut flash, &PartitionTable::default(), "nvs").unwrap(), Some(2));
+        assert!(accept_after_boots(&mut flash, &layout, "nvs", 3).unwrap());
+        assert_eq!(crate::get_ota_state(&mut flash).unwrap(), EspOTAState::Valid);
+        assert_eq!(store::read_value(&mut flash, &PartitionTable::default(), "nvs").unwrap(), None);
+
+        // With rollback the bootloader marked the entry `PendingVerify` and won't boot it again unaccepted
+        update(&mut flash, &image).unwrap();
+        flash.set_ota_data(1, 2, EspOTAState::PendingVerify);
+        assert!(accept_after_boots(&mut flash, &layout, "nvs", 3).unwrap());
+        assert_eq!(crate::get_ota_seq(&mut flash).unwrap(), 2);
+        assert_eq!(crate::get_ota_state(&mut flash).unwrap(), EspOTAState::Valid);
+    }
+
+    #[test]
+    fn updates_failing_in_a_row_fall_back_to_factory() {
+        let _serial = serial();
+        let layout = OtaLayout {
+            failure_counter: Some("nvs"),
+            ..OtaLayout::default()
+        };
+        let image = image(1000);
+        let update = |flash: &mut MockFlash| {
+            let options = OtaOptions {
+                layout,
+                ..OtaOptions::default()
+            };
+            block_on(ota_begin_with_options(flash, SliceReader::new(&image), |_| {}, options)).unwrap();
+        };
+        let mut flash = MockFlash::new(2);
+        update(&mut flash);
+        ota_accept_with_layout(&mut flash, &layout).unwrap();
+        assert_eq!(failure_count(&mut flash, "nvs").unwrap(), 0);
+
+        for failures in 1..=3 {
+            assert!(!check_failure_threshold(&mut flash, &layout, 3).unwrap());
+            update(&mut flash);
+            // The update never boots successfully, so the bootloader aborts it and boots the accepted seq 1 again
+            let copy = (0..2)
+                .find(|&copy| EspOTAData::try_from(<[u8; 32]>::try_from(flash.ota_data(copy)).unwrap()).is_ok_and(|data| data.seq == 2))
+                .unwrap();
+            flash.set_ota_data(copy, 2, EspOTAState::Aborted);
+            ota_accept_with_layout(&mut flash, &layout).unwrap();
+            assert_eq!(get_ota_seq(&mut flash).unwrap(), 1);
+            assert_eq!(failure_count(&mut flash, "nvs").unwrap(), failures);
+        }
+
+        assert!(check_failure_threshold(&mut flash, &layout, 3).unwrap());
+        assert_eq!(get_ota_seq(&mut flash).unwrap(), 0);
+        assert_eq!(failure_count(&mut flash, "nvs").unwrap(), 0);
+    }
+}
@@ -0,0 +1,68 @@
+use crate::error::OtaInternalError;
+use crate::partitions::find_partition_by_name_at;
+use crate::retry_flash;
+use embedded_storage::nor_flash::NorFlash;
+use esp_partition_table::{NorFlashOpError, PartitionEntry, PartitionTable};
+
+/// Read a value persisted in the named data partition of the partition table.
+/// Values are stored in the first word of the partition, which survives OTA updates.
+/// Returns `None` if no value was stored, i.e. the word is erased.
+pub fn read_value<S: NorFlash>(
+    storage: &mut S,
+    table: &PartitionTable,
+    name: &str,
+) -> Result<Option<u32>, OtaInternalError<S>> {
+    let part = find_partition_by_name_at(storage, table, name)?;
+    let mut buffer = [0; 32];
+    let len = 4usize.next_multiple_of(S::READ_SIZE);
+    if len > buffer.len() {
+        return Err(OtaInternalError::Misaligned { offset: part.offset });
+    }
+    retry_flash(|| storage.read(part.offset, &mut buffer[..len]))
+        .map_err(|e| NorFlashOpError::StorageError(e))?;
+
+    let value = u32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]);
+    Ok((value != u32::MAX).then_some(value))
+}
+
+/// Persist a value in the named data partition.
+/// The value is padded with erased bytes to a multiple of `S::WRITE_SIZE`.
+pub fn write_value<S: NorFlash>(
+    storage: &mut S,
+    table: &PartitionTable,
+    name: &str,
+    value: u32,
+) -> Result<(), OtaInternalError<S>> {
+    let part = erase_value(storage, table, name)?;
+    let mut buffer = [0xFF; 32];
+    let len = 4usize.next_multiple_of(S::WRITE_SIZE);
+    if len > buffer.len() {
+        return Err(OtaInternalError::Misaligned { offset: part.offset });
+    }
+    buffer[..4].copy_from_slice(&value.to_le_bytes());
+    retry_flash(|| storage.write(part.offset, &buffer[..len]))
+        .map_err(|e| NorFlashOpError::StorageError(e))?;
+    Ok(())
+}
+
+/// Clear the value persisted in the named data partition
+pub fn clear_value<S: NorFlash>(
+    storage: &mut S,
+    table: &PartitionTable,
+    name: &str,
+) -> Result<(), OtaInternalError<S>> {
+    erase_value(storage, table, name)?;
+    Ok(())
+}
+
+/// Erase the first `S::ERASE_SIZE` bytes of the partition, which hold the value. Returns the partition it is stored in
+fn erase_value<S: NorFlash>(
+    storage: &mut S,
+    table: &PartitionTable,
+    name: &str,
+) -> Result<PartitionEntry, OtaInternalError<S>> {
+    let part = find_partition_by_name_at(storage, table, name)?;
+    retry_flash(|| storage.erase(part.offset, part.offset + S::ERASE_SIZE as u32))
+        .map_err(|e| NorFlashOpError::StorageError(e))?;
+    Ok(part)
+}
@@ -0,0 +1,362 @@
+//! In-memory flash with a partition table, shared by the tests of all modules.
+//! Not every test, or every feature combination, uses every helper.
+#![allow(dead_code)]
+
+use crate::ota_data_structs::{EspOTAData, EspOTAState};
+use core::convert::Infallible;
+use embedded_storage::nor_flash::{
+    ErrorType, MultiwriteNorFlash, NorFlash, NorFlashErrorKind, ReadNorFlash,
+};
+use esp_partition_table::{AppPartitionType, DataPartitionType, PartitionEntry, PartitionType};
+use std::sync::{Mutex, MutexGuard};
+use std::vec;
+use std::vec::Vec;
+
+/// Offset of the otadata partition, its two copies are one sector apart
+pub const OTA_DATA: u32 = 0xd000;
+/// Offset of the factory app partition
+pub const FACTORY: u32 = 0x10000;
+/// Size of every app partition
+pub const APP_SIZE: usize = 0x100000;
+
+/// Offset of ota app partition `ota_<slot>`
+pub const fn slot_offset(slot: u8) -> u32 {
+    FACTORY + (slot as u32 + 1) * APP_SIZE as u32
+}
+
+/// A NOR flash in memory, which like real flash can only clear bits when writing
+#[derive(Debug)]
+pub struct MockFlash {
+    pub data: Vec<u8>,
+    /// Amount of erases and writes that succeed before every further one fails, to simulate a power loss
+    pub ops_until_power_loss: Option<usize>,
+    pub erases: usize,
+    pub writes: usize,
+    /// Amount of reads of the partition table, one per entry while scanning it
+    pub table_reads: usize,
+    /// Offset of a sector that erases silently leave untouched, like flash whose erase fails without an error
+    pub stuck_sector: Option<u32>,
+    /// Value erases fill the flash with. Writes clear bits for the usual `0xFF` and set bits otherwise
+    pub erased_byte: u8,
+    /// Amount of reads outside the partition table that fail before reads succeed again, to simulate transient errors
+    pub failing_reads: usize,
+}
+
+impl MockFlash {
+    /// A partition table with a factory app and `slots` ota app partitions
+    pub fn new(slots: u8) -> Self {
+        Self::with_table(true, slots)
+    }
+
+    /// A partition table with only `slots` ota app partitions, so blank otadata boots `ota_0`
+    pub fn without_factory(slots: u8) -> Self {
+        Self::with_table(false, slots)
+    }
+
+    fn with_table(factory: bool, slots: u8) -> Self {
+        let mut flash = Self {
+            data: vec![0xFF; slot_offset(slots) as usize],
+            ops_until_power_loss: None,
+            erases: 0,
+            writes: 0,
+            table_reads: 0,
+            stuck_sector: None,
+            erased_byte: 0xFF,
+            failing_reads: 0,
+        };
+        let mut entries = vec![
+            PartitionEntry::new(
+                PartitionType::Data(DataPartitionType::Nvs),
+                0x9000,
+                0x4000,
+                "nvs",
+                false,
+            ),
+            PartitionEntry::new(
+                PartitionType::Data(DataPartitionType::Ota),
+                OTA_DATA,
+                0x2000,
+                "otadata",
+                false,
+            ),
+        ];
+        if factory {
+            entries.push(PartitionEntry::new(
+                PartitionType::App(AppPartitionType::Factory),
+                FACTORY,
+                APP_SIZE,
+                "factory",
+                false,
+            ));
+        }
+        for slot in 0..slots {
+            entries.push(PartitionEntry::new(
+                PartitionType::App(AppPartitionType::Ota(slot)),
+                slot_offset(slot),
+                APP_SIZE,
+                std::format!("ota_{slot}"),
+                false,
+            ));
+        }
+        for (i, entry) in entries.into_iter().enumerate() {
+            flash.set_partition(i, entry.unwrap());
+        }
+        flash
+    }
+
+    /// Overwrite entry `index` of the partition table
+    pub fn set_partition(&mut self, index: usize, entry: PartitionEntry) {
+        let offset = 0x8000 + index * 32;
+        let buffer = (&mut self.data[offset..offset + 32]).try_into().unwrap();
+        entry.to_bytes(buffer).unwrap();
+    }
+
+    /// Write a boot entry into copy `copy` (0 or 1) of the otadata partition, bypassing the crate
+    pub fn set_ota_data(&mut self, copy: usize, seq: u32, state: EspOTAState) {
+        let mut entry = EspOTAData::new(seq, [0xFF; 20]);
+        entry.state = state;
+        let offset = OTA_DATA as usize + copy * crate::SECTOR_SIZE;
+        self.data[offset..offset + 32].copy_from_slice(&<[u8; 32]>::from(entry));
+    }
+
+    /// Write the same boot entry into both copies of the otadata partition
+    pub fn set_ota_data_both(&mut self, seq: u32, state: EspOTAState) {
+        self.set_ota_data(0, seq, state);
+        self.set_ota_data(1, seq, state);
+    }
+
+    /// The raw 32 bytes of copy `copy` of the otadata partition
+    pub fn ota_data(&self, copy: usize) -> &[u8] {
+        let offset = OTA_DATA as usize + copy * crate::SECTOR_SIZE;
+        &self.data[offset..offset + 32]
+    }
+
+    /// The bytes at the start of ota app partition `ota_<slot>`
+    pub fn slot(&self, slot: u8, len: usize) -> &[u8] {
+        let offset = slot_offset(slot) as usize;
+        &self.data[offset..offset + len]
+    }
+
+    /// Write `image` into ota app partition `ota_<slot>`, bypassing the crate
+    pub fn set_slot(&mut self, slot: u8, image: &[u8]) {
+        let offset = slot_offset(slot) as usize;
+        self.data[offset..offset + image.len()].copy_from_slice(image);
+    }
+
+    fn operation(&mut self) -> Result<(), NorFlashErrorKind> {
+        match &mut self.ops_until_power_loss {
+            Some(0) => Err(NorFlashErrorKind::Other),
+            Some(ops) => {
+                *ops -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl ErrorType for MockFlash {
+    type Error = NorFlashErrorKind;
+}
+
+impl ReadNorFlash for MockFlash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let from = offset as usize;
+        if (0x8000..0x9000).contains(&from) {
+            self.table_reads += 1;
+        } else if self.failing_reads > 0 {
+            self.failing_reads -= 1;
+            return Err(NorFlashErrorKind::Other);
+        }
+        let data = self
+            .data
+            .get(from..from + bytes.len())
+            .ok_or(NorFlashErrorKind::OutOfBounds)?;
+        bytes.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl NorFlash for MockFlash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = 4096;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        assert!(
+            (from as usize).is_multiple_of(Self::ERASE_SIZE)
+                && (to as usize).is_multiple_of(Self::ERASE_SIZE)
+        );
+        self.operation()?;
+        self.erases += 1;
+        let stuck = self.stuck_sector.filter(|sector| (from..to).contains(sector));
+        let stuck_data = stuck.map(|sector| {
+            let sector = sector as usize;
+            self.data[sector..sector + Self::ERASE_SIZE].to_vec()
+        });
+        self.data[from as usize..to as usize].fill(self.erased_byte);
+        if let (Some(sector), Some(data)) = (stuck, stuck_data) {
+            self.data[sector as usize..sector as usize + Self::ERASE_SIZE].copy_from_slice(&data);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        assert!(
+            (offset as usize).is_multiple_of(Self::WRITE_SIZE)
+                && bytes.len().is_multiple_of(Self::WRITE_SIZE)
+        );
+        self.operation()?;
+        self.writes += 1;
+        let data = &mut self.data[offset as usize..offset as usize + bytes.len()];
+        for (old, new) in data.iter_mut().zip(bytes) {
+            match self.erased_byte {
+                0xFF => *old &= new,
+                _ => *old |= new,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MultiwriteNorFlash for MockFlash {}
+
+#[cfg(feature = "async-flash")]
+impl embedded_storage_async::nor_flash::ReadNorFlash for MockFlash {
+    const READ_SIZE: usize = 1;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        ReadNorFlash::read(self, offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+}
+
+#[cfg(feature = "async-flash")]
+impl embedded_storage_async::nor_flash::NorFlash for MockFlash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = 4096;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        NorFlash::erase(self, from, to)
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        NorFlash::write(self, offset, bytes)
+    }
+}
+
+/// A minimal ESP image with a single segment of `segment_len` bytes and no appended digest
+pub fn image(segment_len: usize) -> Vec<u8> {
+    build_image(segment_len, false)
+}
+
+/// A minimal ESP image with a single segment of `segment_len` bytes and the SHA-256 digest appended, like esptool does
+#[cfg(feature = "sha256")]
+pub fn image_with_hash(segment_len: usize) -> Vec<u8> {
+    build_image(segment_len, true)
+}
+
+/// A minimal ESP image like `image`, whose segment starts with an app description with these fields
+pub fn image_with_app_description(secure_version: u32, version: &str, project_name: &str) -> Vec<u8> {
+    let mut image = image(256);
+    let desc = &mut image[32..32 + 256];
+    desc[0..4].copy_from_slice(&0xABCD5432u32.to_le_bytes());
+    desc[4..8].copy_from_slice(&secure_version.to_le_bytes());
+    desc[16..16 + version.len()].copy_from_slice(version.as_bytes());
+    desc[16 + version.len()..48].fill(0);
+    desc[48..48 + project_name.len()].copy_from_slice(project_name.as_bytes());
+    desc[48 + project_name.len()..80].fill(0);
+    image
+}
+
+fn build_image(segment_len: usize, hash_appended: bool) -> Vec<u8> {
+    // Image header, with one segment and the hash_appended flag at byte 23
+    let mut image = vec![0; 24];
+    image[0] = crate::ESP_IMAGE_MAGIC;
+    image[1] = 1;
+    image[23] = hash_appended as u8;
+    // Segment header and data
+    image.extend_from_slice(&0x3c00_0000u32.to_le_bytes());
+    image.extend_from_slice(&(segment_len as u32).to_le_bytes());
+    image.extend((0..segment_len).map(|i| (i * 7) as u8));
+    // Padded so the checksum byte ends on a 16 byte boundary
+    while !(image.len() + 1).is_multiple_of(16) {
+        image.push(0);
+    }
+    image.push(0xEF);
+    if hash_appended {
+        #[cfg(feature = "sha256")]
+        {
+            use sha2::Digest;
+            let digest = sha2::Sha256::digest(&image);
+            image.extend_from_slice(&digest);
+        }
+    }
+    image
+}
+
+/// An async reader that returns `data` in reads of at most `chunk` bytes
+pub struct SliceReader<'a> {
+    pub data: &'a [u8],
+    pub chunk: usize,
+}
+
+impl<'a> SliceReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, chunk: 1000 }
+    }
+}
+
+impl embedded_io_async::ErrorType for SliceReader<'_> {
+    type Error = Infallible;
+}
+
+impl embedded_io_async::Read for SliceReader<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let len = buf.len().min(self.chunk).min(self.data.len());
+        buf[..len].copy_from_slice(&self.data[..len]);
+        self.data = &self.data[len..];
+        Ok(len)
+    }
+}
+
+/// An async reader whose every read fails
+pub struct FailingReader;
+
+impl embedded_io_async::ErrorType for FailingReader {
+    type Error = embedded_io_async::ErrorKind;
+}
+
+impl embedded_io_async::Read for FailingReader {
+    async fn read(&mut self, _buf: &mut [u8]) -> Result<usize, Self::Error> {
+        Err(embedded_io_async::ErrorKind::Other)
+    }
+}
+
+/// Poll `future` to completion, the futures of this crate never wait for a wake-up
+pub fn block_on<F: core::future::Future>(future: F) -> F::Output {
+    crate::blocking::block_on(future)
+}
+
+/// Update `flash` with `image` using `ota_begin`
+pub fn update(
+    flash: &mut MockFlash,
+    image: &[u8],
+) -> Result<crate::OtaOutcome, crate::error::OtaUpdateError<MockFlash, Infallible>> {
+    block_on(crate::ota_begin(flash, SliceReader::new(image), |_| {}))
+}
+
+/// Serializes the tests that use the global state of the crate, such as the in-progress flag of updates.
+/// Every test that starts an update or reads the flag must hold this.
+pub fn serial() -> MutexGuard<'static, ()> {
+    static LOCK: Mutex<()> = Mutex::new(());
+    LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
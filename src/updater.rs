@@ -0,0 +1,115 @@
+//! Updates of a flash that is owned by a handle, instead of being guarded by the global in-progress flag.
+
+use crate::error::{OtaInternalError, OtaUpdateError};
+use crate::partitions::OtaLayout;
+use crate::{
+    ota_accept_unguarded, ota_begin_unguarded, ota_reject_with_layout, NoHooks, OtaOptions,
+    OtaOutcome, UpdateGuard, SECTOR_SIZE,
+};
+use embedded_io_async::Read;
+use embedded_storage::nor_flash::NorFlash;
+
+/// Owns a flash and updates it, as an alternative to the free functions such as `ota_begin`.
+/// The free functions share a single global flag to reject concurrent updates, so an update of one flash
+/// also blocks an update of another, e.g. an external SPI flash with its own partition table.
+/// An `OtaUpdater` needs no flag: its methods take `&mut self`, so the borrow checker guarantees
+/// that only one update of its flash is in progress, independent of any other updater or the free functions.
+/// The exception is an update that sets `OtaOptions::flash_retries`, which overrides a global setting,
+/// so it takes the global flag like the free functions and fails with `AlreadyUpdating` while another update runs.
+pub struct OtaUpdater<S: NorFlash> {
+    storage: S,
+}
+
+impl<S: NorFlash> OtaUpdater<S> {
+    /// Take ownership of the flash to update
+    pub fn new(storage: S) -> Self {
+        Self { storage }
+    }
+
+    /// The flash, e.g. to read it with the other functions of this crate
+    pub fn storage(&mut self) -> &mut S {
+        &mut self.storage
+    }
+
+    /// Returns the flash
+    pub fn into_inner(self) -> S {
+        self.storage
+    }
+
+    /// Starts a new OTA update, see the free function `ota_begin`
+    pub async fn ota_begin<R: Read>(
+        &mut self,
+        binary: R,
+        progress_fn: impl FnMut(usize),
+    ) -> Result<OtaOutcome, OtaUpdateError<S, R::Error>> {
+        self.ota_begin_with_options(binary, progress_fn, OtaOptions::default())
+            .await
+    }
+
+    /// Starts a new OTA update with the behaviour configured in `options`, see the free function `ota_begin_with_options`
+    pub async fn ota_begin_with_options<R: Read>(
+        &mut self,
+        binary: R,
+        mut progress_fn: impl FnMut(usize),
+        options: OtaOptions<'_>,
+    ) -> Result<OtaOutcome, OtaUpdateError<S, R::Error>> {
+        let _guard = match options.flash_retries {
+            Some(_) => Some(UpdateGuard::acquire().ok_or(OtaUpdateError::AlreadyUpdating)?),
+            None => None,
+        };
+        let mut buffer = [0; SECTOR_SIZE];
+        let progress_fn = async |written| progress_fn(written);
+        ota_begin_unguarded(
+            &mut self.storage,
+            binary,
+            progress_fn,
+            options,
+            &mut buffer,
+            &mut NoHooks,
+        )
+        .await
+    }
+
+    /// Mark OTA update as valid, see the free function `ota_accept`
+    pub fn ota_accept(&mut self) -> Result<(), OtaInternalError<S>> {
+        self.ota_accept_with_layout(&OtaLayout::default())
+    }
+
+    /// Mark OTA update as valid, for a partition table with the given layout
+    pub fn ota_accept_with_layout(&mut self, layout: &OtaLayout) -> Result<(), OtaInternalError<S>> {
+        ota_accept_unguarded(&mut self.storage, layout)
+    }
+
+    /// Explicitly mark an OTA update as invalid, see the free function `ota_reject`
+    pub fn ota_reject(&mut self) -> Result<(), OtaInternalError<S>> {
+        self.ota_reject_with_layout(&OtaLayout::default())
+    }
+
+    /// Mark OTA update as invalid, for a partition table with the given layout
+    pub fn ota_reject_with_layout(&mut self, layout: &OtaLayout) -> Result<(), OtaInternalError<S>> {
+        ota_reject_with_layout(&mut self.storage, layout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{block_on, image, serial, MockFlash, SliceReader};
+
+    #[test]
+    fn only_updates_that_override_the_retries_take_the_global_flag() {
+        let _serial = serial();
+        let image = image(1000);
+        let mut updater = OtaUpdater::new(MockFlash::new(2));
+        let _guard = UpdateGuard::acquire().unwrap();
+
+        let options = OtaOptions {
+            flash_retries: Some(3),
+            ..OtaOptions::default()
+        };
+        let result = block_on(updater.ota_begin_with_options(SliceReader::new(&image), |_| {}, options));
+        assert!(matches!(result, Err(OtaUpdateError::AlreadyUpdating)));
+
+        block_on(updater.ota_begin(SliceReader::new(&image), |_| {})).unwrap();
+    }
+}
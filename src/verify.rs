@@ -0,0 +1,272 @@
+use crate::error::OtaInternalError;
+use crate::image::{ImageParser, HASH_SIZE, IMAGE_HEADER_SIZE};
+use crate::{retry_flash, SECTOR_SIZE};
+use embedded_storage::nor_flash::NorFlash;
+use esp_partition_table::{NorFlashOpError, PartitionEntry};
+
+/// Verifies the SHA-256 digest that esptool appends to an image.
+/// The digest covers the image up to and including the checksum byte,
+/// it does not cover the digest itself or any padding after it.
+#[derive(Clone)]
+pub(crate) struct ImageHashVerifier {
+    parser: ImageParser,
+    position: usize,
+    sha: sha2::Sha256,
+    appended: [u8; HASH_SIZE],
+}
+
+impl ImageHashVerifier {
+    pub(crate) fn new() -> Self {
+        use sha2::Digest;
+        Self {
+            parser: ImageParser::new(),
+            position: 0,
+            sha: sha2::Sha256::new(),
+            appended: [0; HASH_SIZE],
+        }
+    }
+
+    /// Feed the next chunk of the image to the verifier
+    pub(crate) fn feed(&mut self, data: &[u8]) {
+        use sha2::Digest;
+
+        // Feeding the parser first is sufficient to know the image length if this chunk extends past it,
+        // since the last segment header is always in front of the end of the image.
+        let start = self.position;
+        self.parser.feed(data);
+        self.position += data.len();
+
+        let Some(image_len) = self.parser.image_len() else {
+            self.sha.update(data);
+            return;
+        };
+        let hashed = data.len().min(image_len.saturating_sub(start));
+        self.sha.update(&data[..hashed]);
+
+        let from = start.max(image_len);
+        let to = self.position.min(image_len + HASH_SIZE);
+        if from < to {
+            self.appended[from - image_len..to - image_len]
+                .copy_from_slice(&data[from - start..to - start]);
+        }
+    }
+
+    /// Returns true once enough of the image has been fed to call `finish`,
+    /// i.e. the appended digest was received, or it is known that there is none.
+    pub(crate) fn is_complete(&self) -> bool {
+        if self.parser.is_invalid()
+            || (self.position >= IMAGE_HEADER_SIZE && !self.parser.hash_appended())
+        {
+            return true;
+        }
+        self.parser
+            .image_len()
+            .is_some_and(|image_len| self.position >= image_len + HASH_SIZE)
+    }
+
+    /// Returns `None` if the image does not have an appended digest,
+    /// otherwise whether the appended digest matches the image.
+    pub(crate) fn finish(self) -> Option<bool> {
+        use sha2::Digest;
+
+        if !self.parser.hash_appended() {
+            return None;
+        }
+        let Some(image_len) = self.parser.image_len() else {
+            return Some(false);
+        };
+        if self.position < image_len + HASH_SIZE {
+            return Some(false);
+        }
+        Some(self.sha.finalize().as_slice() == self.appended)
+    }
+}
+
+/// Result of a step of the `IncrementalVerifier`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum VerifyStatus {
+    /// Verification has not finished yet, call `step` again
+    Pending,
+    /// Verification has finished, true if the appended digest matches the image.
+    /// Images without an appended digest do not pass verification.
+    Done(bool),
+}
+
+/// Verifies the SHA-256 digest appended to the image in a partition a few sectors at a time,
+/// so verification can be interleaved with other work instead of blocking until the whole image is read.
+#[derive(Clone)]
+pub struct IncrementalVerifier {
+    partition: PartitionEntry,
+    sectors_per_step: usize,
+    offset: usize,
+    verifier: ImageHashVerifier,
+    result: Option<bool>,
+}
+
+impl IncrementalVerifier {
+    /// Verify the image in `partition`, reading at most `sectors_per_step` sectors per call to `step`.
+    /// Use `get_booted_partition` to verify the running image.
+    pub fn new(partition: PartitionEntry, sectors_per_step: usize) -> Self {
+        Self {
+            partition,
+            sectors_per_step: sectors_per_step.max(1),
+            offset: 0,
+            verifier: ImageHashVerifier::new(),
+            result: None,
+        }
+    }
+
+    /// Hash the next sectors of the image
+    pub fn step<S: NorFlash>(
+        &mut self,
+        storage: &mut S,
+    ) -> Result<VerifyStatus, OtaInternalError<S>> {
+        let mut buffer = [0; SECTOR_SIZE];
+        for _ in 0..self.sectors_per_step {
+            if self.result.is_some() {
+                break;
+            }
+            if self.verifier.is_complete() {
+                self.result = Some(self.verifier.clone().finish() == Some(true));
+                break;
+            }
+            if self.offset >= self.partition.size {
+                self.result = Some(false);
+                break;
+            }
+
+            let len = SECTOR_SIZE.min(self.partition.size - self.offset);
+            retry_flash(|| storage.read(self.partition.offset + self.offset as u32, &mut buffer[..len]))
+                .map_err(|e| NorFlashOpError::StorageError(e))?;
+            self.verifier.feed(&buffer[..len]);
+            self.offset += len;
+        }
+
+        Ok(match self.result {
+            Some(result) => VerifyStatus::Done(result),
+            None => VerifyStatus::Pending,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    fn verify(image: &[u8], chunk: usize) -> Option<bool> {
+        let mut verifier = ImageHashVerifier::new();
+        for data in image.chunks(chunk) {
+            verifier.feed(data);
+        }
+        verifier.finish()
+    }
+
+    #[test]
+    fn digest_covers_the_image_without_the_appended_digest() {
+        use sha2::Digest;
+        let image = image_with_hash(1000);
+        let (image_bytes, appended) = image.split_at(image.len() - HASH_SIZE);
+        assert_eq!(sha2::Sha256::digest(image_bytes).as_slice(), appended);
+        assert_ne!(sha2::Sha256::digest(&image).as_slice(), appended);
+
+        for chunk in [1, 7, 32, 1000, image.len()] {
+            assert_eq!(verify(&image, chunk), Some(true), "chunk size {chunk}");
+        }
+    }
+
+    #[test]
+    fn padding_after_the_appended_digest_is_ignored() {
+        let mut image = image_with_hash(1000);
+        image.extend_from_slice(&[0xFF; 100]);
+        assert_eq!(verify(&image, 64), Some(true));
+    }
+
+    #[test]
+    fn corrupt_image_or_digest_is_rejected() {
+        let image = image_with_hash(1000);
+        let mut corrupt_image = image.clone();
+        corrupt_image[100] ^= 1;
+        assert_eq!(verify(&corrupt_image, 64), Some(false));
+
+        let mut corrupt_digest = image.clone();
+        *corrupt_digest.last_mut().unwrap() ^= 1;
+        assert_eq!(verify(&corrupt_digest, 64), Some(false));
+
+        assert_eq!(verify(&image[..image.len() - 1], 64), Some(false));
+    }
+
+    #[test]
+    fn image_without_digest_is_not_verified() {
+        assert_eq!(verify(&image(1000), 64), None);
+    }
+
+    #[test]
+    fn update_with_corrupt_digest_fails() {
+        let _serial = serial();
+        let mut flash = MockFlash::new(2);
+        flash.set_ota_data_both(1, crate::EspOTAState::Valid);
+        let mut image = image_with_hash(1000);
+        image[100] ^= 1;
+        assert!(matches!(
+            update(&mut flash, &image),
+            Err(crate::error::OtaUpdateError::HashMismatch)
+        ));
+        assert_eq!(crate::get_ota_seq(&mut flash).unwrap(), 1);
+    }
+
+    #[test]
+    fn incremental_verifier_hashes_the_partition_over_several_steps() {
+        let verify_slot = |flash: &mut MockFlash| {
+            let partition = crate::partitions::find_partition_by_name(flash, "ota_0").unwrap();
+            let mut verifier = IncrementalVerifier::new(partition, 1);
+            let mut steps = 1;
+            loop {
+                match verifier.step(flash).unwrap() {
+                    VerifyStatus::Pending => steps += 1,
+                    VerifyStatus::Done(valid) => return (valid, steps),
+                }
+            }
+        };
+
+        // The image ends in the middle of its third sector
+        let image = image_with_hash(2 * SECTOR_SIZE + 1000);
+        let mut flash = MockFlash::new(2);
+        flash.set_slot(0, &image);
+        let (valid, steps) = verify_slot(&mut flash);
+        assert!(valid);
+        assert!(steps >= 3, "{steps} steps");
+
+        let mut corrupt = image.clone();
+        corrupt[SECTOR_SIZE + 100] ^= 1;
+        flash.set_slot(0, &corrupt);
+        assert!(!verify_slot(&mut flash).0);
+    }
+
+    #[test]
+    fn update_is_checked_against_a_detached_digest() {
+        use sha2::Digest;
+        let _serial = serial();
+        let image = image(1000);
+        let digest: [u8; 32] = sha2::Sha256::digest(&image).into();
+        let update_with = |flash: &mut MockFlash, expected_sha256| {
+            let options = crate::OtaOptions {
+                expected_sha256: Some(expected_sha256),
+                ..crate::OtaOptions::default()
+            };
+            block_on(crate::ota_begin_with_options(flash, SliceReader::new(&image), |_| {}, options))
+        };
+
+        let mut flash = MockFlash::new(2);
+        let mut wrong = digest;
+        wrong[0] ^= 1;
+        assert!(matches!(
+            update_with(&mut flash, wrong),
+            Err(crate::error::OtaUpdateError::ChecksumMismatch)
+        ));
+        assert_eq!(crate::get_ota_seq(&mut flash).unwrap(), 0);
+
+        update_with(&mut flash, digest).unwrap();
+        assert_eq!(crate::get_ota_seq(&mut flash).unwrap(), 1);
+    }
+}
@@ -0,0 +1,208 @@
+//! Updates that are pushed in chunks as they arrive, for transports that can't be expressed as a single `Read`,
+//! e.g. firmware split over multiple MQTT messages.
+
+use crate::error::{OtaInternalError, OtaUpdateError};
+use crate::image::ESP_IMAGE_MAGIC;
+use crate::ota_data::{read_ota_data, write_ota_data, write_pending_ota_data};
+use crate::ota_data_structs::EspOTAData;
+use crate::partitions::OtaLayout;
+use crate::{next_update_partition, ota_app_partition, recovery, retry_flash, OtaOutcome, UpdateGuard, SECTOR_SIZE};
+use core::convert::Infallible;
+use embedded_storage::nor_flash::NorFlash;
+use esp_partition_table::{NorFlashOpError, PartitionEntry};
+
+/// An update in progress, started with `ota_begin_chunked`.
+/// The image is passed to `write_chunk` in order, in chunks of any size, and committed with `finish`.
+/// Dropping the writer without calling `finish` aborts the update, the old firmware keeps booting, see also `abort`.
+/// Other updates return `AlreadyUpdating` while the writer is alive.
+pub struct OtaWriter<'a, S: NorFlash> {
+    storage: &'a mut S,
+    layout: OtaLayout<'a>,
+    partition: PartitionEntry,
+    new_seq: u32,
+    buffer: [u8; SECTOR_SIZE],
+    buffered: usize,
+    data_written: usize,
+    first_byte: Option<u8>,
+    #[cfg(feature = "sha256")]
+    verifier: crate::verify::ImageHashVerifier,
+    _guard: UpdateGuard,
+}
+
+/// Starts a new OTA update whose image is passed to the returned `OtaWriter` in chunks.
+/// The target partition is erased before this returns, see `ota_begin` for the other checks that are done.
+pub fn ota_begin_chunked<S: NorFlash>(
+    storage: &mut S,
+) -> Result<OtaWriter<'_, S>, OtaUpdateError<S, Infallible>> {
+    ota_begin_chunked_with_layout(storage, OtaLayout::default())
+}
+
+/// Starts a new OTA update in chunks, like `ota_begin_chunked` but for a partition table with the given layout.
+pub fn ota_begin_chunked_with_layout<'a, S: NorFlash>(
+    storage: &'a mut S,
+    layout: OtaLayout<'a>,
+) -> Result<OtaWriter<'a, S>, OtaUpdateError<S, Infallible>> {
+    let Some(guard) = UpdateGuard::acquire() else {
+        return Err(OtaUpdateError::AlreadyUpdating);
+    };
+
+    let ota_data = read_ota_data(storage, &layout)?;
+    if !ota_data.is_valid() {
+        return Err(OtaUpdateError::PendingVerify);
+    }
+    let (new_seq, partition) = next_update_partition(storage, &layout, ota_data.seq)?;
+    if partition.offset == ota_app_partition(storage, &layout, ota_data.seq)?.offset {
+        return Err(OtaUpdateError::WouldEraseRunningPartition);
+    }
+    info!("Starting chunked OTA update. Current sequence is {}, updating to sequence {new_seq} (partition {}).", ota_data.seq, partition.name());
+
+    retry_flash(|| storage.erase(partition.offset, partition.offset + partition.size as u32))
+        .map_err(|e| OtaInternalError::NorFlashOpError(NorFlashOpError::StorageError(e)))?;
+    write_pending_ota_data(storage, &layout, new_seq)?;
+
+    Ok(OtaWriter {
+        storage,
+        layout,
+        partition,
+        new_seq,
+        buffer: [0; SECTOR_SIZE],
+        buffered: 0,
+        data_written: 0,
+        first_byte: None,
+        #[cfg(feature = "sha256")]
+        verifier: crate::verify::ImageHashVerifier::new(),
+        _guard: guard,
+    })
+}
+
+impl<S: NorFlash> OtaWriter<'_, S> {
+    /// Append the next chunk of the image. Data is buffered and written to flash a sector at a time.
+    /// Returns `OutOfSpace` as soon as the image no longer fits the partition.
+    /// After an error the update can't be continued, the writer should be dropped.
+    pub fn write_chunk(&mut self, mut data: &[u8]) -> Result<(), OtaUpdateError<S, Infallible>> {
+        if self.data_written + self.buffered + data.len() > self.partition.size {
+            return Err(OtaUpdateError::OutOfSpace {
+                partition_size: self.partition.size,
+                bytes_written: self.data_written + self.buffered + data.len(),
+            });
+        }
+        if self.first_byte.is_none() {
+            self.first_byte = data.first().copied();
+        }
+        #[cfg(feature = "sha256")]
+        self.verifier.feed(data);
+
+        while !data.is_empty() {
+            let take = data.len().min(SECTOR_SIZE - self.buffered);
+            self.buffer[self.buffered..self.buffered + take].copy_from_slice(&data[..take]);
+            self.buffered += take;
+            data = &data[take..];
+            if self.buffered == SECTOR_SIZE {
+                self.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The amount of bytes passed to `write_chunk` so far
+    pub fn data_written(&self) -> usize {
+        self.data_written + self.buffered
+    }
+
+    /// The partition the image is written to
+    pub fn partition(&self) -> &PartitionEntry {
+        &self.partition
+    }
+
+    /// Write the remaining buffered data and check that the image starts with the image magic and,
+    /// with the `sha256` feature, that its appended digest matches,
+    /// then write the new boot entry. The caller should reboot to activate the new firmware.
+    pub fn finish(mut self) -> Result<OtaOutcome, OtaUpdateError<S, Infallible>> {
+        self.flush()?;
+
+        #[cfg(feature = "sha256")]
+        if self.verifier.finish() == Some(false) {
+            return Err(OtaUpdateError::HashMismatch);
+        }
+        if self.first_byte != Some(ESP_IMAGE_MAGIC) {
+            return Err(OtaUpdateError::InvalidImageMagic);
+        }
+
+        // Counted before the commit like `ota_begin` does, so an error here doesn't fail a committed update
+        if let Some(failure_counter) = self.layout.failure_counter {
+            recovery::increment_failure_count_at(self.storage, &self.layout.table(), failure_counter)?;
+        }
+        let data = EspOTAData::new(self.new_seq, [0xFF; 20]);
+        write_ota_data(self.storage, &self.layout, data)?;
+
+        Ok(OtaOutcome {
+            bytes_written: self.data_written,
+            #[cfg(feature = "sha256")]
+            sha256: None,
+        })
+    }
+
+    /// Cancel the update, e.g. because the server sent a cancel. This is the same as dropping the writer,
+    /// but optionally also erases the partially written partition.
+    /// The boot entry is never updated before `finish`, so the old firmware keeps booting either way,
+    /// and the half-written partition is not bootable but harmless. A new update may be started afterwards.
+    pub fn abort(self, erase_partition: bool) -> Result<(), OtaInternalError<S>> {
+        info!("Aborting chunked OTA update to sequence {} after {} bytes.", self.new_seq, self.data_written());
+        if erase_partition {
+            let (from, to) = (self.partition.offset, self.partition.offset + self.partition.size as u32);
+            retry_flash(|| self.storage.erase(from, to))
+                .map_err(|e| NorFlashOpError::StorageError(e))?;
+        }
+        Ok(())
+    }
+
+    /// Write the buffered data, padded with erased bytes up to a multiple of the flash's `WRITE_SIZE`
+    fn flush(&mut self) -> Result<(), OtaInternalError<S>> {
+        if self.buffered == 0 {
+            return Ok(());
+        }
+        let write_len = self.buffered.next_multiple_of(S::WRITE_SIZE);
+        self.buffer[self.buffered..write_len].fill(0xFF);
+        let offset = self.partition.offset + self.data_written as u32;
+        retry_flash(|| self.storage.write(offset, &self.buffer[0..write_len]))
+            .map_err(|e| NorFlashOpError::StorageError(e))?;
+        self.data_written += self.buffered;
+        self.buffered = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use crate::{get_ota_seq, is_update_in_progress, EspOTAState};
+
+    #[test]
+    fn update_can_start_after_abort() {
+        let _serial = serial();
+        for erase_partition in [false, true] {
+            let mut flash = MockFlash::new(2);
+            flash.set_ota_data_both(1, EspOTAState::Valid);
+            let booted = flash.ota_data(0).to_vec();
+            let image = image(2 * SECTOR_SIZE);
+
+            let mut writer = ota_begin_chunked(&mut flash).unwrap();
+            writer.write_chunk(&image[..SECTOR_SIZE]).unwrap();
+            assert!(matches!(update(&mut MockFlash::new(2), &image), Err(OtaUpdateError::AlreadyUpdating)));
+            writer.abort(erase_partition).unwrap();
+            assert!(!is_update_in_progress());
+
+            assert_eq!(flash.ota_data(0), &booted[..]);
+            assert_eq!(get_ota_seq(&mut flash).unwrap(), 1);
+            let first_sector = flash.slot(1, SECTOR_SIZE);
+            assert_eq!(first_sector == [0xFF; SECTOR_SIZE], erase_partition);
+
+            let mut writer = ota_begin_chunked(&mut flash).unwrap();
+            writer.write_chunk(&image).unwrap();
+            writer.finish().unwrap();
+            assert_eq!(get_ota_seq(&mut flash).unwrap(), 2);
+            assert_eq!(flash.slot(1, image.len()), &image[..]);
+        }
+    }
+}